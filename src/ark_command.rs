@@ -0,0 +1,330 @@
+use std::fmt;
+
+// Reply ARK sends for commands that have no output of their own.
+const NO_RESPONSE: &str = "Server received, But no response!!";
+// Reply to `ListPlayers` on an empty server.
+const NO_PLAYERS: &str = "No Players Connected";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SteamId(pub u64);
+
+impl fmt::Display for SteamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::str::FromStr for SteamId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim().parse().map(SteamId)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Player {
+    pub index: usize,
+    pub name: String,
+    pub steam_id: SteamId,
+}
+
+// Parses `ListPlayers` output, whose lines look like `0. Name, 76561198000000000`.
+// Names may themselves contain commas or dots, so the index is split off at the
+// first `. ` and the Steam ID at the last comma.
+pub fn parse_players(text: &str) -> Result<Vec<Player>, ResponseError> {
+    let text = text.trim();
+    if text.starts_with(NO_PLAYERS) {
+        return Ok(Vec::new());
+    }
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            let unexpected = || ResponseError::Unexpected {
+                command: "ListPlayers",
+                reply: line.to_string(),
+            };
+            let (index, rest) = line.split_once(". ").ok_or_else(unexpected)?;
+            let (name, steam_id) = rest.rsplit_once(',').ok_or_else(unexpected)?;
+            Ok(Player {
+                index: index.trim().parse().map_err(|_| unexpected())?,
+                name: name.trim().to_string(),
+                steam_id: steam_id.parse().map_err(|_| unexpected())?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+impl TimeOfDay {
+    // Only `SetTimeOfDay` uses it, which no Discord command sends yet.
+    #[allow(dead_code)]
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        if hour < 24 && minute < 60 {
+            Some(Self { hour, minute })
+        } else {
+            None
+        }
+    }
+}
+
+// The ARK admin commands the bot sends over RCON. Not all of them are exposed
+// as Discord commands yet; until they are, only the tests build those.
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ArkCommand {
+    SaveWorld,
+    DoExit,
+    ListPlayers,
+    Broadcast(String),
+    ServerChat(String),
+    KickPlayer(SteamId),
+    BanPlayer(SteamId),
+    UnbanPlayer(SteamId),
+    AllowPlayerToJoinNoCheck(SteamId),
+    SetTimeOfDay(TimeOfDay),
+    DestroyWildDinos,
+    GetChat,
+    GetGameLog,
+}
+
+impl ArkCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArkCommand::SaveWorld => "SaveWorld",
+            ArkCommand::DoExit => "DoExit",
+            ArkCommand::ListPlayers => "ListPlayers",
+            ArkCommand::Broadcast(_) => "Broadcast",
+            ArkCommand::ServerChat(_) => "ServerChat",
+            ArkCommand::KickPlayer(_) => "KickPlayer",
+            ArkCommand::BanPlayer(_) => "BanPlayer",
+            ArkCommand::UnbanPlayer(_) => "UnbanPlayer",
+            ArkCommand::AllowPlayerToJoinNoCheck(_) => "AllowPlayerToJoinNoCheck",
+            ArkCommand::SetTimeOfDay(_) => "SetTimeOfDay",
+            ArkCommand::DestroyWildDinos => "DestroyWildDinos",
+            ArkCommand::GetChat => "GetChat",
+            ArkCommand::GetGameLog => "GetGameLog",
+        }
+    }
+
+    // The console line sent over RCON.
+    pub fn to_rcon(&self) -> String {
+        match self {
+            ArkCommand::Broadcast(text) | ArkCommand::ServerChat(text) => {
+                format!("{} {}", self.name(), escape_text(text))
+            }
+            ArkCommand::KickPlayer(id)
+            | ArkCommand::BanPlayer(id)
+            | ArkCommand::UnbanPlayer(id)
+            | ArkCommand::AllowPlayerToJoinNoCheck(id) => format!("{} {}", self.name(), id),
+            ArkCommand::SetTimeOfDay(time) => {
+                format!("{} {:02}:{:02}", self.name(), time.hour, time.minute)
+            }
+            _ => self.name().to_string(),
+        }
+    }
+
+    pub fn parse_response(&self, raw: &str) -> Result<ArkResponse, ResponseError> {
+        let text = raw.trim();
+        if text.is_empty() {
+            return Err(ResponseError::Empty);
+        }
+        let unexpected = || ResponseError::Unexpected {
+            command: self.name(),
+            reply: text.to_string(),
+        };
+        match self {
+            ArkCommand::SaveWorld => {
+                if text.starts_with("World Saved") {
+                    Ok(ArkResponse::WorldSaved)
+                } else {
+                    Err(unexpected())
+                }
+            }
+            ArkCommand::DoExit => {
+                if text.starts_with("Exiting") {
+                    Ok(ArkResponse::Exiting)
+                } else {
+                    Err(unexpected())
+                }
+            }
+            ArkCommand::ListPlayers => parse_players(text).map(ArkResponse::Players),
+            ArkCommand::GetChat | ArkCommand::GetGameLog => {
+                if text == NO_RESPONSE {
+                    Ok(ArkResponse::Lines(Vec::new()))
+                } else {
+                    Ok(ArkResponse::Lines(lines(text)))
+                }
+            }
+            _ => Ok(ArkResponse::Ack(text.to_string())),
+        }
+    }
+}
+
+// Console commands are line based, so a message must not be able to end the
+// command early or smuggle in a second one.
+fn escape_text(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ArkResponse {
+    WorldSaved,
+    Exiting,
+    Players(Vec<Player>),
+    Lines(Vec<String>),
+    // Free-form acknowledgement of a command without structured output.
+    Ack(String),
+}
+
+impl fmt::Display for ArkResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArkResponse::WorldSaved => write!(f, "World Saved"),
+            ArkResponse::Exiting => write!(f, "Exiting..."),
+            ArkResponse::Players(players) if players.is_empty() => write!(f, "{}", NO_PLAYERS),
+            ArkResponse::Players(players) => {
+                let names: Vec<&str> = players.iter().map(|p| p.name.as_str()).collect();
+                write!(f, "{}", names.join("\n"))
+            }
+            ArkResponse::Lines(lines) => write!(f, "{}", lines.join("\n")),
+            ArkResponse::Ack(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ResponseError {
+    // ARK replied with nothing, which usually means it did not run the command.
+    Empty,
+    Unexpected {
+        command: &'static str,
+        reply: String,
+    },
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::Empty => write!(f, "the server returned no output"),
+            ResponseError::Unexpected { command, reply } => {
+                write!(f, "unexpected reply to {}: {}", command, reply)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(index: usize, name: &str, steam_id: u64) -> Player {
+        Player {
+            index,
+            name: name.to_string(),
+            steam_id: SteamId(steam_id),
+        }
+    }
+
+    #[test]
+    fn parses_player_list() {
+        let raw = "\n0. Akh, 76561198012345678\n1. ふわふわ, 76561198087654321 \n";
+        assert_eq!(
+            parse_players(raw).unwrap(),
+            vec![
+                player(0, "Akh", 76561198012345678),
+                player(1, "ふわふわ", 76561198087654321),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_empty_server() {
+        assert_eq!(parse_players("No Players Connected \n").unwrap(), vec![]);
+        assert_eq!(
+            ArkCommand::ListPlayers
+                .parse_response("No Players Connected\n")
+                .unwrap(),
+            ArkResponse::Players(vec![])
+        );
+    }
+
+    #[test]
+    fn keeps_commas_and_dots_in_names() {
+        let raw = "0. Rex, the Tamer, 76561198012345678\n12. Dr. Dodo, 76561198087654321";
+        assert_eq!(
+            parse_players(raw).unwrap(),
+            vec![
+                player(0, "Rex, the Tamer", 76561198012345678),
+                player(12, "Dr. Dodo", 76561198087654321),
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_player_and_time_arguments() {
+        let id = SteamId(76561198012345678);
+        assert_eq!(
+            ArkCommand::KickPlayer(id).to_rcon(),
+            "KickPlayer 76561198012345678"
+        );
+        assert_eq!(
+            ArkCommand::AllowPlayerToJoinNoCheck(id).to_rcon(),
+            "AllowPlayerToJoinNoCheck 76561198012345678"
+        );
+        let time = TimeOfDay::new(7, 5).unwrap();
+        assert_eq!(
+            ArkCommand::SetTimeOfDay(time).to_rcon(),
+            "SetTimeOfDay 07:05"
+        );
+        assert!(TimeOfDay::new(24, 0).is_none());
+        assert!(TimeOfDay::new(12, 60).is_none());
+        assert_eq!(
+            ArkCommand::ServerChat("hi\nSaveWorld".to_string()).to_rcon(),
+            "ServerChat hi SaveWorld"
+        );
+    }
+
+    #[test]
+    fn parses_chat_and_game_log() {
+        for command in [ArkCommand::GetChat, ArkCommand::GetGameLog] {
+            assert_eq!(
+                command
+                    .parse_response("Server received, But no response!! \n")
+                    .unwrap(),
+                ArkResponse::Lines(vec![])
+            );
+            assert_eq!(
+                command.parse_response("Akh: hello\n\nDodo: hi \n").unwrap(),
+                ArkResponse::Lines(vec!["Akh: hello".to_string(), "Dodo: hi".to_string()])
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_players("0. Akh").is_err());
+        assert!(parse_players("Akh, 76561198012345678").is_err());
+        assert!(parse_players("0. Akh, not-an-id").is_err());
+    }
+}
@@ -0,0 +1,813 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{copy, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{debug, info, warn, Span};
+use walkdir::WalkDir;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use crate::catalog;
+use crate::config::{BackupFormat, Compression, Config, ServerConfig};
+use crate::crypto::{self, Encryptor, Key};
+use crate::disk;
+use crate::error::{BotError, BotResult};
+use crate::manifest::{self, BackupInfo, FileRecord, Trigger, ZipManifest};
+use crate::retention::{self, BackupEntry};
+use crate::save_rules::{self, SaveRules};
+use crate::snapshot;
+use crate::verify;
+
+// Backups are named after the local time they were taken, e.g. `2022-12-21_(16-11-21).zip`.
+pub const NAME_FORMAT: &str = "%Y-%m-%d_(%H-%M-%S)";
+
+// Appended to backups while they are written. Anything with this suffix is
+// unfinished and is removed at startup.
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+// Save files are copied into the archive this many bytes at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// How far a running backup is, in bytes of save data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub done: u64,
+    pub total: u64,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u64 {
+        // Nothing to copy counts as done.
+        (self.done * 100).checked_div(self.total).unwrap_or(100)
+    }
+}
+
+// Zips the server's save data into a new backup and applies the retention
+// policy. The work runs on a blocking worker so large save files neither stall
+// the runtime nor have to fit in memory; `progress` follows it as it goes.
+// `trigger` and `players`, the number of players online, are recorded in the
+// manifest. When the backup volume may not have room for the backup, backups
+// due to be pruned anyway are pruned first, and failing that the backup is
+// refused. Backups taken before a rollback skip both retention passes, which
+// could otherwise prune the backup about to be restored.
+pub async fn create_backup(
+    config: &Config,
+    server: &ServerConfig,
+    trigger: Trigger,
+    players: Option<usize>,
+    progress: watch::Sender<Progress>,
+) -> BotResult<PathBuf> {
+    let policy = config.backup.clone();
+    let server = server.clone();
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let key = Key::for_server(&server)?;
+        let date = unused_name(&server.backup_dir);
+        let rules = save_rules(&server)?;
+        let files = save_files(&server, &rules)?;
+        let total = files.iter().map(|f| f.size).sum();
+        disk::make_room(
+            (trigger != Trigger::PreRollback).then_some(&policy),
+            &server.backup_dir,
+            &date,
+            disk::estimate(total),
+            disk::available,
+        )?;
+        let info = BackupInfo {
+            trigger: Some(trigger),
+            players,
+            ..backup_info(&server, &files, rules.map)
+        };
+        let dest = match server.format {
+            BackupFormat::Zip => {
+                let dest = server.backup_dir.join(format!("{}.zip", date));
+                info!(
+                    dest = %dest.display(),
+                    compression = server.compression.name(),
+                    level = server.compression_level,
+                    encrypted = key.is_some(),
+                    "backup started"
+                );
+                let options = file_options(server.compression, server.compression_level);
+                let partial = partial_path(&dest);
+                let written = match &key {
+                    Some(key) => File::create(&partial)
+                        .and_then(|file| Encryptor::new(key, file))
+                        .map_err(BotError::from)
+                        .and_then(|file| write_archive(file, &files, &info, options, &progress))
+                        .and_then(|file| Ok(file.finish()?.sync_all()?)),
+                    None => File::create(&partial)
+                        .map_err(BotError::from)
+                        .and_then(|file| write_archive(file, &files, &info, options, &progress))
+                        .and_then(|file| Ok(file.sync_all()?)),
+                };
+                if let Err(why) = written {
+                    let _ = std::fs::remove_file(&partial);
+                    return Err(why);
+                }
+                commit_partial(
+                    &server.backup_dir,
+                    &date,
+                    &partial,
+                    &dest,
+                    false,
+                    key.as_ref(),
+                )?;
+                dest
+            }
+            BackupFormat::Dedup => {
+                info!(name = %date, "snapshot started");
+                snapshot::write_snapshot(&server.backup_dir, &date, &files, &info, &progress)?
+            }
+        };
+        if trigger != Trigger::PreRollback {
+            retention::prune(&policy, &server.backup_dir, false)?;
+        }
+        // The backup is complete without its catalog record, which the next
+        // listing adds from the manifest anyway.
+        if let Err(why) = catalog::index(&server.backup_dir, key.as_ref()) {
+            warn!(error = %why, "could not update the backup catalog");
+        }
+        info!(dest = %dest.display(), ?trigger, "backup finished");
+        Ok(dest)
+    })
+    .await
+    .map_err(|e| BotError::Io(e.into()))?
+}
+
+// A name for a backup taken now. Backups are named to the second, so one taken
+// within a second of the last waits for the next rather than replace it.
+fn unused_name(backup_dir: &Path) -> String {
+    loop {
+        let name = chrono::Local::now().format(NAME_FORMAT).to_string();
+        let zip = backup_dir.join(format!("{}.zip", name));
+        if !zip.exists() && !snapshot::manifest_path(backup_dir, &name).exists() {
+            return name;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    PathBuf::from(partial)
+}
+
+// Re-reads the backup written to `partial`, decrypting it with `key` if it is
+// encrypted, and, if it is intact, gives it its final name. A backup that fails
+// verification is deleted rather than left where `/rollback` could pick it up.
+pub fn commit_partial(
+    backup_dir: &Path,
+    name: &str,
+    partial: &Path,
+    dest: &Path,
+    snapshot: bool,
+    key: Option<&Key>,
+) -> BotResult<()> {
+    let verification = verify::verify(backup_dir, name, partial, snapshot, key);
+    if !verification.is_ok() {
+        std::fs::remove_file(partial)?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "backup {} failed verification: {}",
+                name,
+                verification.problems.join("; ")
+            ),
+        )
+        .into());
+    }
+    std::fs::rename(partial, dest)?;
+    sync_dir(backup_dir)?;
+    Ok(())
+}
+
+// Makes a rename in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened for syncing on Windows, where NTFS journals
+// renames itself.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Deletes the partial files a crash or a full disk left in `backup_dir`,
+// including partial chunks of the dedup store. Returns how many there were.
+pub fn remove_partials(backup_dir: &Path) -> BotResult<usize> {
+    let mut removed = 0;
+    for entry in WalkDir::new(backup_dir).into_iter().filter_map(|e| e.ok()) {
+        let is_partial = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|n| n.ends_with(PARTIAL_SUFFIX));
+        if is_partial && entry.file_type().is_file() {
+            std::fs::remove_file(entry.path())?;
+            info!(path = %entry.path().display(), "removed partial backup file");
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BenchmarkResult {
+    pub compression: Compression,
+    pub level: Option<i32>,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+// Compresses the current save data with every codec without writing anything
+// to disk, so the codec for `compression` can be chosen with real numbers.
+// Returns the size of the save data and one result per codec.
+pub async fn benchmark(server: &ServerConfig) -> BotResult<(u64, Vec<BenchmarkResult>)> {
+    let server = server.clone();
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let rules = save_rules(&server)?;
+        let files = save_files(&server, &rules)?;
+        // Sized like the manifest of a `/save` backup.
+        let info = BackupInfo {
+            trigger: Some(Trigger::Manual),
+            ..backup_info(&server, &files, rules.map)
+        };
+        let total = files.iter().map(|f| f.size).sum();
+        let (progress, _) = watch::channel(Progress::default());
+        let mut results = Vec::new();
+        for compression in Compression::ALL {
+            // The configured level is benchmarked for the configured codec.
+            let level = if compression == server.compression {
+                server.compression_level
+            } else {
+                None
+            };
+            let started = Instant::now();
+            let sink = write_archive(
+                SizeCounter::default(),
+                &files,
+                &info,
+                file_options(compression, level),
+                &progress,
+            )?;
+            let result = BenchmarkResult {
+                compression,
+                level,
+                bytes: sink.len,
+                elapsed: started.elapsed(),
+            };
+            info!(?result, "benchmarked compression");
+            results.push(result);
+        }
+        Ok((total, results))
+    })
+    .await
+    .map_err(|e| BotError::Io(e.into()))?
+}
+
+fn file_options(compression: Compression, level: Option<i32>) -> FileOptions {
+    let method = match compression {
+        Compression::Stored => CompressionMethod::Stored,
+        Compression::Deflate => CompressionMethod::Deflated,
+        Compression::Bzip2 => CompressionMethod::Bzip2,
+        Compression::Zstd => CompressionMethod::Zstd,
+    };
+    FileOptions::default()
+        .compression_method(method)
+        .compression_level(level)
+        // Save files easily exceed 4 GiB uncompressed.
+        .large_file(true)
+}
+
+// An entry of a backup: a directory, or a file with its size.
+pub struct SaveFile {
+    // The path relative to the save data directory, as stored in the backup.
+    pub name: String,
+    // `None` for directories.
+    pub path: Option<PathBuf>,
+    pub size: u64,
+}
+
+// The metadata the save files and the server profile tell about `files`; the
+// caller adds what only it knows. Unless the map is known, it is taken to be
+// the largest `.ark` file at the top of the save directory; the other maps'
+// saves sit next to it but are smaller, as only the running map is written to
+// regularly.
+fn backup_info(server: &ServerConfig, files: &[SaveFile], map: Option<String>) -> BackupInfo {
+    let map = map.or_else(|| {
+        files
+            .iter()
+            .filter(|f| f.path.is_some() && !f.name.contains('/'))
+            .filter_map(|f| Some((f.name.strip_suffix(".ark")?, f.size)))
+            .max_by_key(|(_, size)| *size)
+            .map(|(name, _)| name.to_string())
+    });
+    let server_version = server.version_file.as_ref().and_then(
+        |path| match std::fs::read_to_string(path) {
+            Ok(version) => Some(version.trim().to_string()),
+            Err(why) => {
+                warn!(path = %path.display(), error = %why, "could not read the server version");
+                None
+            }
+        },
+    );
+    BackupInfo {
+        map,
+        server_version,
+        ..Default::default()
+    }
+}
+
+// A directory or file `save_files` takes files from.
+struct Root {
+    walk: PathBuf,
+    // Names in the backup are relative to this, after `prefix`.
+    base: PathBuf,
+    prefix: Option<&'static str>,
+    // Settings files are backed up whole, without the rules.
+    filtered: bool,
+}
+
+// The files the rules select from the save directory and the cluster
+// directory, and the server settings, preceded by the directories they are in.
+fn save_files(server: &ServerConfig, rules: &SaveRules) -> BotResult<Vec<SaveFile>> {
+    let mut roots = vec![Root {
+        walk: server.savedata_path.clone(),
+        base: server.savedata_path.clone(),
+        prefix: None,
+        filtered: true,
+    }];
+    if let Some(cluster_dir) = &server.cluster_dir {
+        roots.push(Root {
+            walk: cluster_dir.clone(),
+            base: cluster_dir.clone(),
+            prefix: Some(save_rules::CLUSTER_DIR),
+            filtered: true,
+        });
+    }
+    for path in save_rules::config_paths(server) {
+        if !path.exists() {
+            debug!(path = %path.display(), "settings file not found, not backing it up");
+            continue;
+        }
+        roots.push(Root {
+            base: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            walk: path,
+            prefix: Some(save_rules::CONFIG_DIR),
+            filtered: false,
+        });
+    }
+    let mut dirs = BTreeSet::new();
+    let mut files = Vec::new();
+    for root in roots {
+        for entry in WalkDir::new(&root.walk).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let name = match (save_rules::backup_name(&root.base, path), root.prefix) {
+                (Some(name), Some(prefix)) => format!("{}/{}", prefix, name),
+                (Some(name), None) => name,
+                (None, _) => {
+                    warn!(path = %path.display(), "skipping a file whose name is not UTF-8");
+                    continue;
+                }
+            };
+            if root.filtered && !rules.matches(&name) {
+                continue;
+            }
+            let mut dir = name.as_str();
+            while let Some((parent, _)) = dir.rsplit_once('/') {
+                dirs.insert(parent.to_string());
+                dir = parent;
+            }
+            let size = entry.metadata().map_err(std::io::Error::from)?.len();
+            files.push(SaveFile {
+                name,
+                path: Some(path.to_path_buf()),
+                size,
+            });
+        }
+    }
+    let dirs = dirs.into_iter().map(|name| SaveFile {
+        name,
+        path: None,
+        size: 0,
+    });
+    Ok(dirs.chain(files).collect())
+}
+
+// The rules for `server`. Its patterns were checked when the config was
+// loaded, so this only fails if the map name makes an invalid pattern.
+fn save_rules(server: &ServerConfig) -> BotResult<SaveRules> {
+    SaveRules::new(server).map_err(|why| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, why.to_string()).into()
+    })
+}
+
+// Writes `files` and a manifest of them into a zip archive.
+fn write_archive<W: Write + Seek>(
+    writer: W,
+    files: &[SaveFile],
+    info: &BackupInfo,
+    options: FileOptions,
+    progress: &watch::Sender<Progress>,
+) -> BotResult<W> {
+    let total = files.iter().map(|f| f.size).sum();
+    let mut done = 0;
+    progress.send_replace(Progress { done, total });
+    let mut zip = zip::ZipWriter::new(writer);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut records = Vec::new();
+    for file in files {
+        let path = match &file.path {
+            Some(path) => path,
+            None => {
+                zip.add_directory(file.name.as_str(), options)?;
+                continue;
+            }
+        };
+        debug!(file = %file.name, "adding to backup");
+        zip.start_file(file.name.as_str(), options)?;
+        let mut f = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        loop {
+            let n = f.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            zip.write_all(&buffer[..n])?;
+            hasher.update(&buffer[..n]);
+            size += n as u64;
+            done += n as u64;
+            progress.send_replace(Progress { done, total });
+        }
+        records.push(FileRecord {
+            path: file.name.clone(),
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        });
+    }
+    let manifest = ZipManifest {
+        version: manifest::MANIFEST_VERSION,
+        info: info.clone(),
+        files: records,
+    };
+    zip.start_file(manifest::MANIFEST_ENTRY, options)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(std::io::Error::from)?;
+    Ok(zip.finish()?)
+}
+
+// A writer that only keeps track of how long the output would be.
+#[derive(Default)]
+struct SizeCounter {
+    pos: u64,
+    len: u64,
+}
+
+impl Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SizeCounter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+// The server's backups with their labels, pins and catalog records, newest
+// first.
+pub fn list_backups(server: &ServerConfig) -> BotResult<Vec<BackupEntry>> {
+    catalog::index(&server.backup_dir, Key::for_server(server)?.as_ref())
+}
+
+// Restores the backup `name` (a zip or a snapshot) over the server's save data,
+// and over its settings files too with `with_config`. An encrypted zip is
+// authenticated whole first, so a tampered one is refused before any file is
+// overwritten.
+pub fn restore_backup(server: &ServerConfig, name: &str, with_config: bool) -> BotResult<()> {
+    let zip_fullpath = server.backup_dir.join(format!("{}.zip", name));
+    let manifest = snapshot::manifest_path(&server.backup_dir, name);
+    // The name comes from the user, so it must not point outside the backup directory.
+    if name.contains(['/', '\\']) || name.contains("..") {
+        return Err(BotError::NotFound(format!("バックアップ `{}`", name)));
+    }
+    if manifest.is_file() {
+        info!(backup = name, with_config, "restoring snapshot");
+        return snapshot::restore_snapshot(&server.backup_dir, &manifest, |path| {
+            save_rules::restore_path(server, path, with_config)
+        });
+    }
+    if !zip_fullpath.is_file() {
+        return Err(BotError::NotFound(format!("バックアップ `{}`", name)));
+    }
+    info!(backup = name, with_config, "restoring backup");
+    let mut file = crypto::open(&zip_fullpath, Key::for_server(server)?.as_ref())?;
+    file.authenticate()?;
+
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.name() == manifest::MANIFEST_ENTRY {
+            continue;
+        }
+        let outpath = match file
+            .enclosed_name()
+            .and_then(|path| save_rules::restore_path(server, path, with_config))
+        {
+            Some(path) => path,
+            None => continue,
+        };
+
+        if (*file.name()).ends_with('/') {
+            debug!(path = %outpath.display(), "restored directory");
+            std::fs::create_dir_all(&outpath)?;
+        } else {
+            debug!(
+                path = %outpath.display(),
+                bytes = file.size(),
+                "restored file"
+            );
+            if let Some(p) = outpath.parent() {
+                if !p.exists() {
+                    std::fs::create_dir_all(p)?;
+                }
+            }
+            let mut outfile = File::create(&outpath)?;
+            copy(&mut file, &mut outfile)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::EncryptionError;
+    use crate::test_support::{config, server_config};
+
+    #[tokio::test]
+    async fn streams_save_files_and_reports_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(server.savedata_path.join("SaveProfiles")).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        // Several chunks, and not a multiple of the chunk size.
+        let map: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), &map).unwrap();
+        std::fs::write(
+            server.savedata_path.join("SaveProfiles/123.arkprofile"),
+            "profile",
+        )
+        .unwrap();
+        let config = config(vec![("island", server.clone())]);
+
+        let (progress, mut updates) = watch::channel(Progress::default());
+        let seen = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            while updates.changed().await.is_ok() {
+                seen.push(*updates.borrow_and_update());
+            }
+            seen
+        });
+        let dest = create_backup(&config, &server, Trigger::Manual, None, progress)
+            .await
+            .unwrap();
+        let seen = seen.await.unwrap();
+
+        let total = map.len() as u64 + 7;
+        assert_eq!(seen.last(), Some(&Progress { done: total, total }));
+        assert!(seen.windows(2).all(|w| w[0].done <= w[1].done));
+
+        let mut archive = zip::ZipArchive::new(File::open(dest).unwrap()).unwrap();
+        let mut restored = Vec::new();
+        archive
+            .by_name("TheIsland.ark")
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, map);
+        assert_eq!(
+            archive
+                .by_name("SaveProfiles/123.arkprofile")
+                .unwrap()
+                .size(),
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn benchmark_sizes_match_real_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server_config(dir.path());
+        server.compression = Compression::Zstd;
+        server.compression_level = Some(19);
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        let map = "dodo ".repeat(100_000);
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), &map).unwrap();
+        let config = config(vec![("island", server.clone())]);
+
+        let (original, results) = benchmark(&server).await.unwrap();
+        assert_eq!(original, map.len() as u64);
+        let codecs: Vec<_> = results.iter().map(|r| (r.compression, r.level)).collect();
+        assert_eq!(
+            codecs,
+            vec![
+                (Compression::Stored, None),
+                (Compression::Deflate, None),
+                (Compression::Bzip2, None),
+                (Compression::Zstd, Some(19)),
+            ]
+        );
+        assert!(results[0].bytes > original);
+        assert!(results[3].bytes < original / 100);
+
+        let (progress, _) = watch::channel(Progress::default());
+        let dest = create_backup(&config, &server, Trigger::Manual, None, progress)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&dest).unwrap().len(), results[3].bytes);
+        let mut archive = zip::ZipArchive::new(File::open(dest).unwrap()).unwrap();
+        let file = archive.by_name("TheIsland.ark").unwrap();
+        assert_eq!(file.compression(), CompressionMethod::Zstd);
+    }
+
+    #[tokio::test]
+    async fn only_verified_backups_get_their_final_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(server.backup_dir.join("objects/ab")).unwrap();
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), "map").unwrap();
+        let config = config(vec![("island", server.clone())]);
+
+        // Left behind by a crash.
+        let stale = server.backup_dir.join("2000-01-01_(00-00-00).zip.partial");
+        std::fs::write(&stale, "truncat").unwrap();
+        std::fs::write(server.backup_dir.join("objects/ab/abcd.partial"), "").unwrap();
+        assert_eq!(remove_partials(&server.backup_dir).unwrap(), 2);
+        assert!(!stale.exists());
+
+        let (progress, _) = watch::channel(Progress::default());
+        let dest = create_backup(&config, &server, Trigger::Manual, None, progress)
+            .await
+            .unwrap();
+        assert!(dest.is_file());
+        assert!(!partial_path(&dest).exists());
+
+        // A partial that does not verify is deleted instead of renamed.
+        let broken = server.backup_dir.join("2000-01-02_(00-00-00).zip");
+        let partial = partial_path(&broken);
+        std::fs::write(&partial, "not a zip").unwrap();
+        assert!(
+            commit_partial(&server.backup_dir, "broken", &partial, &broken, false, None).is_err()
+        );
+        assert!(!partial.exists() && !broken.exists());
+    }
+
+    #[tokio::test]
+    async fn encrypted_backups_restore_and_refuse_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server_config(dir.path());
+        let key_file = dir.path().join("backup_key");
+        std::fs::write(&key_file, format!("{}\n", "ab".repeat(32))).unwrap();
+        server.encryption_key_file = Some(key_file);
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        // Entries span several chunks and start in the middle of them, so the
+        // archive is encrypted as it is written, not as a whole afterwards.
+        let save = server.savedata_path.join("TheIsland.ark");
+        let mut state = 1u32;
+        let dodo: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 24) as u8
+            })
+            .collect();
+        std::fs::write(&save, &dodo).unwrap();
+        std::fs::create_dir_all(server.savedata_path.join("SaveProfiles")).unwrap();
+        for i in 0..3 {
+            let profile = format!("SaveProfiles/{}.arkprofile", i);
+            std::fs::write(server.savedata_path.join(profile), &dodo[..70_000]).unwrap();
+        }
+        let config = config(vec![("island", server.clone())]);
+
+        let (progress, _) = watch::channel(Progress::default());
+        let dest = create_backup(&config, &server, Trigger::Manual, Some(1), progress)
+            .await
+            .unwrap();
+        assert!(zip::ZipArchive::new(File::open(&dest).unwrap()).is_err());
+        // Nothing unencrypted was written beside it.
+        let names: Vec<_> = std::fs::read_dir(&server.backup_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert!(names
+            .iter()
+            .all(|n| !n.to_string_lossy().contains("partial")));
+        let entries = list_backups(&server).unwrap();
+        assert_eq!(entries[0].info.map.as_deref(), Some("TheIsland"));
+        let name = dest.file_stem().unwrap().to_str().unwrap();
+
+        std::fs::write(&save, "rex").unwrap();
+        restore_backup(&server, name, false).unwrap();
+        assert_eq!(std::fs::read(&save).unwrap(), dodo);
+
+        let mut keyless = server.clone();
+        keyless.encryption_key_file = None;
+        assert!(matches!(
+            restore_backup(&keyless, name, false),
+            Err(BotError::Encryption(EncryptionError::KeyRequired))
+        ));
+
+        let mut raw = std::fs::read(&dest).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        std::fs::write(&dest, raw).unwrap();
+        std::fs::write(&save, "rex").unwrap();
+        assert!(matches!(
+            restore_backup(&server, name, false),
+            Err(BotError::Encryption(EncryptionError::Rejected))
+        ));
+        assert_eq!(std::fs::read_to_string(&save).unwrap(), "rex");
+    }
+
+    #[tokio::test]
+    async fn backs_up_the_active_map_and_cluster_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server_config(dir.path());
+        server.map = Some("Fjordur".to_string());
+        server.cluster_dir = Some(dir.path().join("cluster"));
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(dir.path().join("cluster/fuwa")).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        for name in [
+            "Fjordur.ark",
+            "Fjordur_21.12.2022_16.11.21.ark",
+            "Ragnarok.ark",
+            "123.arkprofile",
+            "123.profilebak",
+            "456.arktribe",
+        ] {
+            std::fs::write(server.savedata_path.join(name), name).unwrap();
+        }
+        let upload = dir.path().join("cluster/fuwa/76561198000000000");
+        std::fs::write(&upload, "dodo").unwrap();
+        let config = config(vec![("fjordur", server.clone())]);
+
+        let (progress, _) = watch::channel(Progress::default());
+        let dest = create_backup(&config, &server, Trigger::Manual, None, progress)
+            .await
+            .unwrap();
+        let archive = zip::ZipArchive::new(File::open(&dest).unwrap()).unwrap();
+        let mut names: Vec<_> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                manifest::MANIFEST_ENTRY,
+                "123.arkprofile",
+                "456.arktribe",
+                "Fjordur.ark",
+                "clusters/",
+                "clusters/fuwa/",
+                "clusters/fuwa/76561198000000000",
+            ]
+        );
+
+        std::fs::remove_file(&upload).unwrap();
+        let name = dest.file_stem().unwrap().to_str().unwrap();
+        restore_backup(&server, name, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&upload).unwrap(), "dodo");
+        assert!(!server.savedata_path.join("clusters").exists());
+    }
+}
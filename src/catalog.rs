@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::backup;
+use crate::crypto::{self, Key};
+use crate::error::{BotError, BotResult};
+use crate::manifest::{self, BackupInfo, Trigger, ZipManifest};
+use crate::retention::{self, BackupEntry};
+use crate::service::Notice;
+use crate::snapshot::Manifest;
+
+// What is known about each backup in a directory, keyed by backup name, so
+// `/listbackups` can sort and filter without opening every archive. Records
+// are read from the backups' manifests, and backups made before manifests had
+// a trigger simply have none.
+const CATALOG_FILE: &str = "catalog.json";
+
+// `/listbackups` shows this many backups per page.
+pub const PAGE_SIZE: usize = 20;
+
+// Serialises read-modify-write cycles on the catalog files.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub time: NaiveDateTime,
+    pub size: u64,
+    pub snapshot: bool,
+    #[serde(flatten)]
+    pub info: BackupInfo,
+}
+
+pub fn load(backup_dir: &Path) -> BotResult<BTreeMap<String, Record>> {
+    let path = backup_dir.join(CATALOG_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let raw = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&raw).map_err(std::io::Error::from)?)
+}
+
+// Brings the catalog in line with the backups on disk: records backups it does
+// not know yet and drops those that are gone. Returns the backups, newest
+// first, with what the catalog knows about them. Encrypted backups are read
+// with `key`.
+pub fn index(backup_dir: &Path, key: Option<&Key>) -> BotResult<Vec<BackupEntry>> {
+    let _locked = LOCK.lock().unwrap();
+    let mut entries = retention::scan(backup_dir)?;
+    let mut records = load(backup_dir)?;
+    let before = records.clone();
+    records.retain(|name, _| entries.iter().any(|e| &e.name == name));
+    for entry in &mut entries {
+        if records.contains_key(&entry.name) {
+            continue;
+        }
+        match read_info(entry, key) {
+            Ok(info) => {
+                entry.info = info.clone();
+                records.insert(
+                    entry.name.clone(),
+                    Record {
+                        time: entry.time,
+                        size: entry.size,
+                        snapshot: entry.snapshot,
+                        info,
+                    },
+                );
+            }
+            Err(why) => warn!(backup = %entry.name, error = %why, "could not read the manifest"),
+        }
+    }
+    if records != before {
+        info!(backups = records.len(), "updated the backup catalog");
+        save(backup_dir, &records)?;
+    }
+    Ok(entries)
+}
+
+// Drops the records of backups that no longer exist.
+pub fn forget<'a>(backup_dir: &Path, names: impl IntoIterator<Item = &'a str>) -> BotResult<()> {
+    let _locked = LOCK.lock().unwrap();
+    let mut records = load(backup_dir)?;
+    let before = records.len();
+    for name in names {
+        records.remove(name);
+    }
+    if records.len() == before {
+        return Ok(());
+    }
+    save(backup_dir, &records)
+}
+
+fn save(backup_dir: &Path, records: &BTreeMap<String, Record>) -> BotResult<()> {
+    let path = backup_dir.join(CATALOG_FILE);
+    let partial = backup::partial_path(&path);
+    let raw = serde_json::to_vec_pretty(records).map_err(std::io::Error::from)?;
+    std::fs::write(&partial, raw)?;
+    std::fs::rename(&partial, &path)?;
+    Ok(())
+}
+
+fn read_info(entry: &BackupEntry, key: Option<&Key>) -> BotResult<BackupInfo> {
+    if entry.snapshot {
+        return Ok(Manifest::load(&entry.path)?.info);
+    }
+    let mut archive = ZipArchive::new(crypto::open(&entry.path, key)?)?;
+    let info = match archive.by_name(manifest::MANIFEST_ENTRY) {
+        Ok(file) => {
+            let manifest: ZipManifest =
+                serde_json::from_reader(file).map_err(std::io::Error::from)?;
+            manifest.info
+        }
+        // Made before manifests existed.
+        Err(ZipError::FileNotFound) => BackupInfo::default(),
+        Err(why) => return Err(why.into()),
+    };
+    Ok(info)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+    Largest,
+}
+
+// Which backups `/listbackups` shows, from arguments such as
+// `sort:oldest trigger:manual from:2023-04-01 to:2023-04-30 page:2`. Dates are
+// inclusive and in local time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Query {
+    pub sort: Sort,
+    pub trigger: Option<Trigger>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    // Starting at 1.
+    pub page: usize,
+}
+
+// One page of `/listbackups`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupPage {
+    pub entries: Vec<BackupEntry>,
+    pub page: usize,
+    pub pages: usize,
+    // How many backups matched the query.
+    pub total: usize,
+}
+
+impl Query {
+    pub fn parse(args: &str) -> BotResult<Self> {
+        let usage = || BotError::Precondition(Notice::BackupListUsage);
+        let date = |value: &str| NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| usage());
+        let mut query = Query {
+            page: 1,
+            ..Default::default()
+        };
+        for arg in args.split_whitespace() {
+            let (key, value) = arg.split_once(':').ok_or_else(usage)?;
+            match key {
+                "sort" => {
+                    query.sort = match value {
+                        "newest" => Sort::Newest,
+                        "oldest" => Sort::Oldest,
+                        "size" => Sort::Largest,
+                        _ => return Err(usage()),
+                    }
+                }
+                "trigger" => {
+                    let trigger = Trigger::ALL.into_iter().find(|t| t.name() == value);
+                    query.trigger = Some(trigger.ok_or_else(usage)?);
+                }
+                "from" => query.from = Some(date(value)?),
+                "to" => query.to = Some(date(value)?),
+                "page" => match value.parse() {
+                    Ok(page) if page > 0 => query.page = page,
+                    _ => return Err(usage()),
+                },
+                _ => return Err(usage()),
+            }
+        }
+        Ok(query)
+    }
+
+    // Filters and sorts `entries` (newest first) and cuts out the page asked
+    // for. A page past the end comes back empty.
+    pub fn apply(&self, entries: Vec<BackupEntry>) -> BackupPage {
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .filter(|e| self.trigger.is_none() || e.info.trigger == self.trigger)
+            .filter(|e| self.from.is_none_or(|from| e.time.date() >= from))
+            .filter(|e| self.to.is_none_or(|to| e.time.date() <= to))
+            .collect();
+        match self.sort {
+            Sort::Newest => {}
+            Sort::Oldest => entries.reverse(),
+            Sort::Largest => entries.sort_by_key(|e| std::cmp::Reverse(e.size)),
+        }
+        let total = entries.len();
+        let entries = entries
+            .into_iter()
+            .skip((self.page - 1) * PAGE_SIZE)
+            .take(PAGE_SIZE)
+            .collect();
+        BackupPage {
+            entries,
+            page: self.page,
+            pages: total.div_ceil(PAGE_SIZE).max(1),
+            total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{create_backup, Progress};
+    use crate::test_support::{config, server_config};
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn records_new_and_existing_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), "map").unwrap();
+        let config = config(vec![("island", server.clone())]);
+        for (trigger, players) in [(Trigger::Scheduled, Some(2)), (Trigger::Manual, None)] {
+            let (progress, _) = watch::channel(Progress::default());
+            create_backup(&config, &server, trigger, players, progress)
+                .await
+                .unwrap();
+        }
+        let records = load(&server.backup_dir).unwrap();
+        assert_eq!(records.len(), 2);
+
+        // A lost catalog is rebuilt from the manifests.
+        std::fs::remove_file(server.backup_dir.join(CATALOG_FILE)).unwrap();
+        let entries = index(&server.backup_dir, None).unwrap();
+        assert_eq!(load(&server.backup_dir).unwrap(), records);
+        assert_eq!(entries[0].info.trigger, Some(Trigger::Manual));
+        assert_eq!(entries[1].info.trigger, Some(Trigger::Scheduled));
+        assert_eq!(entries[1].info.players, Some(2));
+        assert_eq!(entries[1].info.map.as_deref(), Some("TheIsland"));
+
+        std::fs::remove_file(&entries[0].path).unwrap();
+        index(&server.backup_dir, None).unwrap();
+        assert_eq!(load(&server.backup_dir).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn filters_sorts_and_paginates() {
+        let day = |d| NaiveDate::from_ymd_opt(2023, 4, d).unwrap();
+        let entries: Vec<BackupEntry> = (1..=30)
+            .rev()
+            .map(|d| BackupEntry {
+                name: format!("2023-04-{:02}", d),
+                path: format!("2023-04-{:02}.zip", d).into(),
+                time: day(d).and_hms_opt(12, 0, 0).unwrap(),
+                size: (d as u64 % 7) * 1024,
+                snapshot: false,
+                info: BackupInfo {
+                    trigger: Some(if d % 2 == 0 {
+                        Trigger::Scheduled
+                    } else {
+                        Trigger::Manual
+                    }),
+                    ..Default::default()
+                },
+                label: None,
+                pinned: false,
+            })
+            .collect();
+        let names = |page: &BackupPage| -> Vec<String> {
+            page.entries.iter().map(|e| e.name.clone()).collect()
+        };
+
+        let page = Query::parse("").unwrap().apply(entries.clone());
+        assert_eq!((page.page, page.pages, page.total), (1, 2, 30));
+        assert_eq!(page.entries.len(), PAGE_SIZE);
+        assert_eq!(page.entries[0].name, "2023-04-30");
+
+        let page = Query::parse("page:2 sort:oldest")
+            .unwrap()
+            .apply(entries.clone());
+        assert_eq!(page.entries.len(), 10);
+        assert_eq!(page.entries[0].name, "2023-04-21");
+
+        let query = Query::parse("trigger:manual from:2023-04-10 to:2023-04-15").unwrap();
+        let page = query.apply(entries.clone());
+        assert_eq!(names(&page), vec!["2023-04-15", "2023-04-13", "2023-04-11"]);
+
+        let page = Query::parse("sort:size to:2023-04-07")
+            .unwrap()
+            .apply(entries.clone());
+        assert_eq!(page.entries[0].name, "2023-04-06");
+
+        for args in ["oldest", "sort:name", "trigger:auto", "from:4/1", "page:0"] {
+            assert!(matches!(
+                Query::parse(args),
+                Err(BotError::Precondition(Notice::BackupListUsage))
+            ));
+        }
+    }
+}
@@ -0,0 +1,675 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::crypto;
+use crate::save_rules;
+
+pub const CONFIG_PATH: &str = "config.toml";
+
+// Settings that used to be compiled into the binary. Everything that differs
+// between hosts lives here so the bot can be moved without recompiling.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub discord: DiscordConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub disk: DiskConfig,
+    #[serde(default)]
+    pub tunnel: TunnelConfig,
+    // Places every server's backups are copied to, besides `backup_dir`.
+    #[serde(default)]
+    pub replicas: Vec<ReplicaConfig>,
+    // One profile per ARK instance, keyed by the name used in commands.
+    pub servers: BTreeMap<String, ServerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub prefix: String,
+    pub admin_role: String,
+    // Where the bot reports problems nobody asked about, such as a backup
+    // volume running out of space. Only logged when unset.
+    pub admin_channel: Option<u64>,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        Self {
+            prefix: "/".to_string(),
+            admin_role: "ARK Server Admin".to_string(),
+            admin_channel: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    // Retention: the newest `keep` backups are always kept, plus the newest
+    // backup of each of the last `keep_hourly` hours, `keep_daily` days and so
+    // on. Anything else is pruned after each backup.
+    pub keep: usize,
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    // Prunes the oldest kept backups until the rest fit, never the newest one.
+    pub max_total_size_mb: Option<u64>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            keep: 10,
+            keep_hourly: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            max_total_size_mb: None,
+        }
+    }
+}
+
+// The free space of every server's backup volume is checked every
+// `check_interval_secs`; the admin channel is told when it drops below
+// `low_space_mb` and again once it recovers.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskConfig {
+    pub low_space_mb: u64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            low_space_mb: 5 * 1024,
+            check_interval_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // A `tracing` filter such as `info` or `fuwa_ark_bot=debug,serenity=warn`.
+    // `RUST_LOG` takes precedence when it is set.
+    pub level: String,
+    // Where the daily rotated JSON log files are written.
+    pub dir: PathBuf,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            dir: PathBuf::from("logs"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    // Where jobs changed with `/schedule` are kept. Once it exists it replaces
+    // the `schedule` lists of the server profiles.
+    pub state_file: PathBuf,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            state_file: PathBuf::from("schedule.json"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    // Prints `0` when the tunnel shared by the servers is down.
+    pub check_script: PathBuf,
+    // Restarts the tunnel for `/reload_connection`.
+    pub restart_script: PathBuf,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            check_script: PathBuf::from("scripts/check_connection.ps1"),
+            restart_script: PathBuf::from("scripts/restart_tunnel.ps1"),
+        }
+    }
+}
+
+// A place backups are copied to after they are taken. Replicas keep backups by
+// their own `retention`, so a replica can hold more history than the game host
+// has room for. Only zip backups are replicated; dedup snapshots stay local.
+// New keys here or in a target go into `check_replica_keys` too.
+#[derive(Debug, Deserialize)]
+pub struct ReplicaConfig {
+    // Used in logs.
+    pub name: String,
+    #[serde(flatten)]
+    pub target: TargetConfig,
+    #[serde(default)]
+    pub retention: BackupConfig,
+    // Failed uploads are retried this many times, waiting `retry_delay_secs`
+    // and twice as long after each further failure.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TargetConfig {
+    // A directory, such as a second disk or a network share. Backups are
+    // stored in a subdirectory per server.
+    Local { path: PathBuf },
+    S3(S3Config),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    // Such as `https://s3.ap-northeast-1.amazonaws.com` or
+    // `http://127.0.0.1:9000` for MinIO. Buckets are addressed path-style.
+    pub endpoint: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    // Prepended to the keys, which are `<prefix>/<server>/<backup file>`.
+    #[serde(default)]
+    pub prefix: String,
+    // Files holding the credentials, like `rcon_password_file`.
+    pub access_key_file: PathBuf,
+    pub secret_key_file: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(default = "default_rcon_address")]
+    pub rcon_address: String,
+    #[serde(default = "default_rcon_password_file")]
+    pub rcon_password_file: PathBuf,
+    pub savedata_path: PathBuf,
+    pub backup_dir: PathBuf,
+    #[serde(default = "default_start_script")]
+    pub start_script: PathBuf,
+    #[serde(default)]
+    pub format: BackupFormat,
+    // Only used by the zip format.
+    #[serde(default)]
+    pub compression: Compression,
+    // The codec's default level is used when unset.
+    pub compression_level: Option<i32>,
+    // The active map, e.g. `TheIsland`. Read from the launch arguments in
+    // `start_script` when unset.
+    pub map: Option<String>,
+    // Where the cluster's transferred characters and items are kept, if the
+    // server is part of a cluster. Stored under `clusters/` in backups.
+    pub cluster_dir: Option<PathBuf>,
+    // Globs for files to back up besides the map save, profiles, tribes and
+    // cluster files, and for files to leave out.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // Server settings stored in a separate `config/` section of each backup,
+    // so a rollback can bring back the settings that matched the save. Files
+    // and directories are both accepted. By default: `Config/WindowsServer`
+    // and `AllowedCheaterSteamIDs.txt` next to `savedata_path`, and
+    // `BanList.txt` in `Binaries/Win64`.
+    pub config_files: Option<Vec<PathBuf>>,
+    // A file holding the ARK server version, such as `version.txt` in the
+    // server install directory. Recorded in backup manifests when set.
+    pub version_file: Option<PathBuf>,
+    // Encrypts zip backups, so copies on replicas cannot be read or altered
+    // unnoticed. The key is 64 hexadecimal digits (`openssl rand -hex 32`),
+    // given here or in a file. Without it, no encrypted backup can be restored.
+    pub encryption_key: Option<String>,
+    pub encryption_key_file: Option<PathBuf>,
+    // With a key, backups that are not encrypted are refused, so one cannot be
+    // swapped in unnoticed. This lets the ones made before the key was set be
+    // restored and verified.
+    #[serde(default)]
+    pub allow_unencrypted_backups: bool,
+    // Cron expressions (with seconds) for SaveWorld and a backup, in local time.
+    #[serde(default = "default_schedule")]
+    pub schedule: Vec<String>,
+    // Discord channels in which this server is the default target.
+    #[serde(default)]
+    pub channels: Vec<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupFormat {
+    // One self-contained zip archive per backup.
+    #[default]
+    Zip,
+    // Chunks shared between backups in a content-addressed store, with one
+    // small manifest per backup.
+    Dedup,
+}
+
+// How backup archives are compressed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Stored,
+    Deflate,
+    #[default]
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 4] = [
+        Compression::Stored,
+        Compression::Deflate,
+        Compression::Bzip2,
+        Compression::Zstd,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Stored => "stored",
+            Compression::Deflate => "deflate",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    // The levels the codec accepts, or `None` if it takes no level.
+    pub fn levels(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Compression::Stored => None,
+            Compression::Deflate | Compression::Bzip2 => Some(1..=9),
+            Compression::Zstd => Some(1..=22),
+        }
+    }
+}
+
+fn default_rcon_address() -> String {
+    "127.0.0.1:32330".to_string()
+}
+
+fn default_rcon_password_file() -> PathBuf {
+    PathBuf::from("rcon_password")
+}
+
+fn default_start_script() -> PathBuf {
+    PathBuf::from("scripts/start_ark_server.ps1")
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_retry_delay_secs() -> u64 {
+    30
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+// Every hour on the hour.
+fn default_schedule() -> Vec<String> {
+    vec!["0 0 * * * *".to_string()]
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    Invalid { key: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Invalid { key, reason } => write!(f, "invalid `{}`: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.into(), e))?;
+        Self::parse(&raw)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self, ConfigError> {
+        check_replica_keys(raw)?;
+        let config: Config = toml::from_str(raw).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Looks up a server by the name given in a command.
+    pub fn server(&self, name: &str) -> Option<(&str, &ServerConfig)> {
+        self.servers
+            .get_key_value(name)
+            .map(|(name, server)| (name.as_str(), server))
+    }
+
+    // The server a command targets when no name is given: the one bound to the
+    // channel, or the only configured server.
+    pub fn default_server(&self, channel_id: u64) -> Option<(&str, &ServerConfig)> {
+        let bound = self
+            .servers
+            .iter()
+            .find(|(_, server)| server.channels.contains(&channel_id));
+        let only = if self.servers.len() == 1 {
+            self.servers.iter().next()
+        } else {
+            None
+        };
+        bound.or(only).map(|(name, server)| (name.as_str(), server))
+    }
+
+    pub fn server_names(&self) -> Vec<&str> {
+        self.servers.keys().map(String::as_str).collect()
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        fn invalid(key: impl Into<String>, reason: &str) -> ConfigError {
+            ConfigError::Invalid {
+                key: key.into(),
+                reason: reason.to_string(),
+            }
+        }
+
+        if self.discord.prefix.trim().is_empty() {
+            return Err(invalid("discord.prefix", "must not be empty"));
+        }
+        if self.discord.admin_role.trim().is_empty() {
+            return Err(invalid("discord.admin_role", "must not be empty"));
+        }
+        validate_retention("backup", &self.backup)?;
+        if EnvFilter::try_new(&self.log.level).is_err() {
+            return Err(invalid("log.level", "is not a valid filter directive"));
+        }
+        if self.log.dir.as_os_str().is_empty() {
+            return Err(invalid("log.dir", "must not be empty"));
+        }
+        if self.schedule.state_file.as_os_str().is_empty() {
+            return Err(invalid("schedule.state_file", "must not be empty"));
+        }
+        if self.tunnel.check_script.as_os_str().is_empty() {
+            return Err(invalid("tunnel.check_script", "must not be empty"));
+        }
+        if self.tunnel.restart_script.as_os_str().is_empty() {
+            return Err(invalid("tunnel.restart_script", "must not be empty"));
+        }
+        if self.disk.check_interval_secs == 0 {
+            return Err(invalid("disk.check_interval_secs", "must be at least 1"));
+        }
+        let mut replica_names = HashSet::new();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            let key = |field: &str| format!("replicas[{}].{}", i, field);
+            if replica.name.trim().is_empty() {
+                return Err(invalid(key("name"), "must not be empty"));
+            }
+            if !replica_names.insert(&replica.name) {
+                return Err(invalid(key("name"), "is used by another replica"));
+            }
+            validate_retention(&key("retention"), &replica.retention)?;
+            match &replica.target {
+                TargetConfig::Local { path } if path.as_os_str().is_empty() => {
+                    return Err(invalid(key("path"), "must not be empty"))
+                }
+                TargetConfig::Local { .. } => {}
+                TargetConfig::S3(s3) => {
+                    if !(s3.endpoint.starts_with("http://") || s3.endpoint.starts_with("https://"))
+                    {
+                        return Err(invalid(key("endpoint"), "expected an http(s) URL"));
+                    }
+                    if s3.bucket.is_empty() || s3.bucket.contains('/') {
+                        return Err(invalid(key("bucket"), "expected a bucket name"));
+                    }
+                }
+            }
+        }
+        if self.servers.is_empty() {
+            return Err(invalid("servers", "at least one server must be configured"));
+        }
+
+        let mut channel_owners: HashMap<u64, &str> = HashMap::new();
+        for (name, server) in &self.servers {
+            let key = |field: &str| format!("servers.{}.{}", name, field);
+            if name.trim().is_empty() || name.contains(char::is_whitespace) {
+                return Err(invalid(
+                    format!("servers.{}", name),
+                    "server names must not contain whitespace",
+                ));
+            }
+            match server.rcon_address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+                _ => return Err(invalid(key("rcon_address"), "expected `host:port`")),
+            }
+            if server.savedata_path.as_os_str().is_empty() {
+                return Err(invalid(key("savedata_path"), "must not be empty"));
+            }
+            if server.backup_dir.as_os_str().is_empty() {
+                return Err(invalid(key("backup_dir"), "must not be empty"));
+            }
+            if let Some(level) = server.compression_level {
+                match server.compression.levels() {
+                    None => {
+                        return Err(invalid(
+                            key("compression_level"),
+                            "`stored` does not take a level",
+                        ))
+                    }
+                    Some(levels) if !levels.contains(&level) => {
+                        return Err(ConfigError::Invalid {
+                            key: key("compression_level"),
+                            reason: format!(
+                                "must be between {} and {} for `{}`",
+                                levels.start(),
+                                levels.end(),
+                                server.compression.name()
+                            ),
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+            match (&server.encryption_key, &server.encryption_key_file) {
+                (Some(_), Some(_)) => {
+                    return Err(invalid(
+                        key("encryption_key"),
+                        "set either `encryption_key` or `encryption_key_file`",
+                    ))
+                }
+                (Some(hex), None) if crypto::Key::parse(hex).is_none() => {
+                    return Err(invalid(
+                        key("encryption_key"),
+                        "expected 64 hexadecimal digits",
+                    ))
+                }
+                (None, None) => {}
+                _ if server.format != BackupFormat::Zip => {
+                    return Err(invalid(
+                        key("format"),
+                        "encryption is only supported for `zip` backups",
+                    ))
+                }
+                _ => {}
+            }
+            for (field, patterns) in [("include", &server.include), ("exclude", &server.exclude)] {
+                if let Err(why) = save_rules::glob_set(patterns) {
+                    return Err(ConfigError::Invalid {
+                        key: key(field),
+                        reason: why.to_string(),
+                    });
+                }
+            }
+            let mut labels = HashMap::new();
+            for path in server.config_files.iter().flatten() {
+                let label = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(label) => label,
+                    None => return Err(invalid(key("config_files"), "paths must end in a name")),
+                };
+                if let Some(other) = labels.insert(label, path) {
+                    return Err(ConfigError::Invalid {
+                        key: key("config_files"),
+                        reason: format!(
+                            "{} and {} have the same name",
+                            other.display(),
+                            path.display()
+                        ),
+                    });
+                }
+            }
+            for expression in &server.schedule {
+                if let Err(why) = cron::Schedule::from_str(expression) {
+                    return Err(ConfigError::Invalid {
+                        key: key("schedule"),
+                        reason: format!("`{}`: {}", expression, why),
+                    });
+                }
+            }
+            for channel in &server.channels {
+                if let Some(other) = channel_owners.insert(*channel, name) {
+                    return Err(ConfigError::Invalid {
+                        key: key("channels"),
+                        reason: format!("channel {} is already assigned to `{}`", channel, other),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A replica's target shares its table, which keeps serde from rejecting
+// unknown keys in it, so a misspelt key such as `retenton` would silently fall
+// back to the default. They are looked for here instead.
+fn check_replica_keys(raw: &str) -> Result<(), ConfigError> {
+    const COMMON: &[&str] = &["name", "kind", "retention", "retries", "retry_delay_secs"];
+    const LOCAL: &[&str] = &["path"];
+    const S3: &[&str] = &[
+        "endpoint",
+        "region",
+        "bucket",
+        "prefix",
+        "access_key_file",
+        "secret_key_file",
+    ];
+
+    let table: toml::Table = toml::from_str(raw).map_err(ConfigError::Parse)?;
+    let replicas = match table.get("replicas").and_then(toml::Value::as_array) {
+        Some(replicas) => replicas,
+        None => return Ok(()),
+    };
+    for (i, replica) in replicas.iter().enumerate() {
+        let replica = match replica.as_table() {
+            Some(replica) => replica,
+            None => continue,
+        };
+        // An unknown kind is left to the parser to report.
+        let target = match replica.get("kind").and_then(toml::Value::as_str) {
+            Some("local") => LOCAL,
+            Some("s3") => S3,
+            _ => continue,
+        };
+        if let Some(key) = replica
+            .keys()
+            .find(|key| !COMMON.contains(&key.as_str()) && !target.contains(&key.as_str()))
+        {
+            return Err(ConfigError::Invalid {
+                key: format!("replicas[{}].{}", i, key),
+                reason: "is not a known setting".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn validate_retention(key: &str, retention: &BackupConfig) -> Result<(), ConfigError> {
+    if retention.keep
+        + retention.keep_hourly
+        + retention.keep_daily
+        + retention.keep_weekly
+        + retention.keep_monthly
+        == 0
+    {
+        return Err(ConfigError::Invalid {
+            key: format!("{}.keep", key),
+            reason: "at least one retention rule must keep a backup".to_string(),
+        });
+    }
+    if retention.max_total_size_mb == Some(0) {
+        return Err(ConfigError::Invalid {
+            key: format!("{}.max_total_size_mb", key),
+            reason: "must be at least 1".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_replica(replica: &str) -> Result<Config, ConfigError> {
+        let example = include_str!("../config.example.toml");
+        Config::parse(&format!("{}\n[[replicas]]\n{}", example, replica))
+    }
+
+    #[test]
+    fn rejects_misspelt_replica_keys() {
+        let s3 = r#"
+name = "offsite"
+kind = "s3"
+endpoint = "https://s3.example.com"
+bucket = "ark"
+access_key_file = "s3_access_key"
+secret_key_file = "s3_secret_key"
+"#;
+        assert!(with_replica(s3).is_ok());
+        let local = "name = \"nas\"\nkind = \"local\"\npath = \"backups\"\n";
+        assert!(with_replica(local).is_ok());
+
+        for (replica, misspelt) in [
+            (
+                s3.replace("access_key_file", "acess_key_file"),
+                "acess_key_file",
+            ),
+            (format!("{}retenton = {{ keep = 3 }}\n", s3), "retenton"),
+            (format!("{}bucket = \"ark\"\n", local), "bucket"),
+        ] {
+            match with_replica(&replica) {
+                Err(ConfigError::Invalid { key, .. }) => {
+                    assert_eq!(key, format!("replicas[0].{}", misspelt))
+                }
+                other => panic!("unexpected result: {:?}", other.err()),
+            }
+        }
+    }
+}
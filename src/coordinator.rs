@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{watch, Mutex as AsyncMutex, MutexGuard};
+use tracing::{debug, Instrument, Span};
+
+use crate::backup::{self, Progress};
+use crate::config::Config;
+use crate::error::{BotError, BotResult};
+use crate::manifest::Trigger;
+use crate::replication::Replicator;
+
+// `None` until the backup has finished.
+type Outcome = Option<Result<PathBuf, Arc<BotError>>>;
+
+// Runs every backup the bot takes, one at a time. Autosave, `/save` and the
+// shutdown commands all go through here, so backup names never collide and
+// retention passes never race.
+//
+// A request made while a backup of the same server for the same trigger is
+// already queued joins that backup instead of adding another: it has not read
+// any save files yet, so it covers the request just as well. Requests for
+// other triggers get their own backup, since the trigger decides whether room
+// is made and old backups are pruned, and it is recorded in the manifest.
+// Requests from `request_own` neither join nor are joined, for callers that
+// mark the backup as theirs afterwards, like `/save` with a label.
+pub struct BackupCoordinator {
+    config: Arc<Config>,
+    replication: Arc<Replicator>,
+    running: Arc<AsyncMutex<()>>,
+    // The backup of each server and trigger that is waiting for `running`.
+    queued: Arc<Mutex<HashMap<(String, Trigger), Job>>>,
+}
+
+#[derive(Clone)]
+struct Job {
+    progress: watch::Receiver<Progress>,
+    outcome: watch::Receiver<Outcome>,
+}
+
+// A caller's handle on the backup that will cover its request.
+pub struct BackupTicket {
+    pub progress: watch::Receiver<Progress>,
+    outcome: watch::Receiver<Outcome>,
+}
+
+impl BackupCoordinator {
+    pub fn new(config: Arc<Config>, replication: Arc<Replicator>) -> Self {
+        Self {
+            config,
+            replication,
+            running: Arc::new(AsyncMutex::new(())),
+            queued: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Queues a backup of the server `name`, or joins the one already queued for
+    // `trigger`. `players` is recorded in the manifest of a new backup; a
+    // request that joins keeps that of the first.
+    pub fn request(&self, name: &str, trigger: Trigger, players: Option<usize>) -> BackupTicket {
+        let key = (name.to_string(), trigger);
+        let mut queued = self.queued.lock().unwrap();
+        if let Some(job) = queued.get(&key) {
+            debug!(server = name, "joining the queued backup");
+            return job.ticket();
+        }
+        let job = self.spawn(key.clone(), players, true);
+        queued.insert(key, job.clone());
+        job.ticket()
+    }
+
+    // Queues a backup of the server `name` that no other request joins.
+    pub fn request_own(
+        &self,
+        name: &str,
+        trigger: Trigger,
+        players: Option<usize>,
+    ) -> BackupTicket {
+        self.spawn((name.to_string(), trigger), players, false)
+            .ticket()
+    }
+
+    // Starts the task that runs a backup once `running` is free. A `joinable`
+    // job is taken out of `queued` when it starts.
+    fn spawn(&self, key: (String, Trigger), players: Option<usize>, joinable: bool) -> Job {
+        let (progress_sender, progress) = watch::channel(Progress::default());
+        let (outcome_sender, outcome) = watch::channel(None);
+        let config = Arc::clone(&self.config);
+        let replication = Arc::clone(&self.replication);
+        let running = Arc::clone(&self.running);
+        let queued = Arc::clone(&self.queued);
+        tokio::spawn(
+            async move {
+                let _running = running.lock().await;
+                if joinable {
+                    // Requests from now on need a backup that starts after this one.
+                    queued.lock().unwrap().remove(&key);
+                }
+                let (name, trigger) = key;
+                let server = &config.servers[&name];
+                let result =
+                    backup::create_backup(&config, server, trigger, players, progress_sender).await;
+                if result.is_ok() {
+                    replication.backed_up();
+                }
+                outcome_sender.send_replace(Some(result.map_err(Arc::new)));
+            }
+            .instrument(Span::current()),
+        );
+        Job { progress, outcome }
+    }
+
+    // Waits for the running backup and keeps new ones from starting while the
+    // guard is held, for operations that must not overlap a backup.
+    pub async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.running.lock().await
+    }
+
+    // Backs up the server `name` at once, under the guard from `lock`, so no
+    // other backup can run between it and whatever the caller does next.
+    pub async fn backup_locked(
+        &self,
+        _guard: &MutexGuard<'_, ()>,
+        name: &str,
+        trigger: Trigger,
+        players: Option<usize>,
+        progress: watch::Sender<Progress>,
+    ) -> BotResult<PathBuf> {
+        let server = &self.config.servers[name];
+        let path = backup::create_backup(&self.config, server, trigger, players, progress).await?;
+        self.replication.backed_up();
+        Ok(path)
+    }
+}
+
+impl Job {
+    fn ticket(&self) -> BackupTicket {
+        BackupTicket {
+            progress: self.progress.clone(),
+            outcome: self.outcome.clone(),
+        }
+    }
+}
+
+impl BackupTicket {
+    // The result of the backup that covered this request.
+    pub async fn finished(mut self) -> BotResult<PathBuf> {
+        loop {
+            if let Some(outcome) = self.outcome.borrow_and_update().clone() {
+                return outcome.map_err(BotError::Shared);
+            }
+            if self.outcome.changed().await.is_err() {
+                return Err(std::io::Error::other("backup task stopped without a result").into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config, server_config};
+
+    #[tokio::test]
+    async fn requests_queued_together_share_one_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), "map").unwrap();
+        let backup_dir = server.backup_dir.clone();
+        let config = Arc::new(config(vec![("island", server)]));
+        let replication = Arc::new(Replicator::new(Arc::clone(&config)));
+        let coordinator = BackupCoordinator::new(config, replication);
+
+        // While something else holds the lock, every request joins one backup.
+        let guard = coordinator.lock().await;
+        let tickets: Vec<_> = (0..3)
+            .map(|_| coordinator.request("island", Trigger::Scheduled, Some(1)))
+            .collect();
+        drop(guard);
+        let mut paths = Vec::new();
+        for ticket in tickets {
+            paths.push(ticket.finished().await.unwrap());
+        }
+        assert!(paths.windows(2).all(|w| w[0] == w[1]));
+        assert_eq!(crate::retention::scan(&backup_dir).unwrap().len(), 1);
+
+        // Once it has run, a new request gets a backup of its own.
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let next = coordinator
+            .request("island", Trigger::Manual, None)
+            .finished()
+            .await
+            .unwrap();
+        assert_ne!(next, paths[0]);
+        assert_eq!(crate::retention::scan(&backup_dir).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn requests_for_other_triggers_get_their_own_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), "map").unwrap();
+        let backup_dir = server.backup_dir.clone();
+        let config = Arc::new(config(vec![("island", server)]));
+        let replication = Arc::new(Replicator::new(Arc::clone(&config)));
+        let coordinator = BackupCoordinator::new(config, replication);
+
+        let guard = coordinator.lock().await;
+        let scheduled = coordinator.request("island", Trigger::Scheduled, Some(1));
+        let rollback = coordinator.request("island", Trigger::PreRollback, None);
+        drop(guard);
+        let scheduled = scheduled.finished().await.unwrap();
+        let rollback = rollback.finished().await.unwrap();
+        assert_ne!(scheduled, rollback);
+        let triggers: Vec<_> = crate::retention::scan(&backup_dir)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.info.trigger)
+            .collect();
+        assert_eq!(triggers.len(), 2);
+        assert!(triggers.contains(&Some(Trigger::Scheduled)));
+        assert!(triggers.contains(&Some(Trigger::PreRollback)));
+    }
+
+    #[tokio::test]
+    async fn own_requests_are_not_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.savedata_path).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        std::fs::write(server.savedata_path.join("TheIsland.ark"), "map").unwrap();
+        let backup_dir = server.backup_dir.clone();
+        let config = Arc::new(config(vec![("island", server)]));
+        let replication = Arc::new(Replicator::new(Arc::clone(&config)));
+        let coordinator = BackupCoordinator::new(config, replication);
+
+        let guard = coordinator.lock().await;
+        let first = coordinator.request_own("island", Trigger::Manual, None);
+        let second = coordinator.request_own("island", Trigger::Manual, None);
+        let shared = coordinator.request("island", Trigger::Manual, None);
+        drop(guard);
+        let first = first.finished().await.unwrap();
+        let second = second.finished().await.unwrap();
+        let shared = shared.finished().await.unwrap();
+        assert_ne!(first, second);
+        assert_ne!(shared, first);
+        assert_ne!(shared, second);
+        assert_eq!(crate::retention::scan(&backup_dir).unwrap().len(), 3);
+    }
+}
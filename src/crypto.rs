@@ -0,0 +1,500 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{NewStream, StreamBE32, StreamPrimitive};
+use chacha20poly1305::aead::{KeyInit, OsRng};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::config::ServerConfig;
+use crate::error::BotResult;
+
+// Encrypted backups are the zip archive sealed in chunks with the STREAM
+// construction over ChaCha20-Poly1305: the magic, a random nonce prefix, then
+// each chunk of the archive followed by its tag. The chunk's position and
+// whether it is the last one are part of its nonce, so chunks cannot be
+// reordered, dropped or cut off without failing authentication. Chunks have a
+// fixed size, which lets the zip reader seek inside the archive.
+const MAGIC: &[u8; 8] = b"FUWAENC1";
+const NONCE_PREFIX_LEN: usize = 7;
+const HEADER_LEN: u64 = (MAGIC.len() + NONCE_PREFIX_LEN) as u64;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+// A 256-bit backup key, written as 64 hexadecimal digits, e.g. the output of
+// `openssl rand -hex 32`.
+#[derive(Clone)]
+pub struct Key {
+    key: chacha20poly1305::Key,
+    // Whether archives that are not encrypted are read too, for backups made
+    // before the key was set. Otherwise they are refused, since anyone could
+    // have written them.
+    allow_unencrypted: bool,
+}
+
+impl Key {
+    pub fn parse(hex: &str) -> Option<Key> {
+        let raw = hex::decode(hex.trim()).ok()?;
+        (raw.len() == 32).then(|| Key {
+            key: *chacha20poly1305::Key::from_slice(&raw),
+            allow_unencrypted: false,
+        })
+    }
+
+    // The key `server` encrypts its backups with, if it has one. A key file is
+    // read each time, like the RCON password.
+    pub fn for_server(server: &ServerConfig) -> BotResult<Option<Key>> {
+        let hex = match (&server.encryption_key, &server.encryption_key_file) {
+            (Some(hex), _) => hex.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?,
+            (None, None) => return Ok(None),
+        };
+        match Key::parse(&hex) {
+            Some(key) => Ok(Some(Key {
+                allow_unencrypted: server.allow_unencrypted_backups,
+                ..key
+            })),
+            None => Err(EncryptionError::InvalidKey.into()),
+        }
+    }
+
+    fn stream(&self, prefix: &[u8]) -> StreamBE32<ChaCha20Poly1305> {
+        StreamBE32::from_aead(ChaCha20Poly1305::new(&self.key), prefix.into())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+    // The backup is encrypted but the server has no key.
+    KeyRequired,
+    // The configured key is not 64 hexadecimal digits.
+    InvalidKey,
+    // The server has a key but the backup is not encrypted, and unencrypted
+    // backups are not allowed.
+    NotEncrypted,
+    // A chunk failed authentication: the backup was modified or truncated, or
+    // it was encrypted with another key.
+    Rejected,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::KeyRequired => write!(f, "the backup is encrypted but no key is set"),
+            EncryptionError::InvalidKey => write!(f, "the key is not 64 hexadecimal digits"),
+            EncryptionError::NotEncrypted => {
+                write!(f, "the backup is not encrypted but a key is set")
+            }
+            EncryptionError::Rejected => write!(
+                f,
+                "the backup failed authentication: it was modified or encrypted with another key"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl EncryptionError {
+    // The error behind `e`, when a decrypting reader failed.
+    pub fn from_io(e: &io::Error) -> Option<EncryptionError> {
+        e.get_ref()?.downcast_ref().copied()
+    }
+}
+
+fn rejected() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, EncryptionError::Rejected)
+}
+
+// Encrypts what is written to it into `inner`, for writers that go back to
+// patch what they wrote, as the zip writer does with the header of each entry
+// once its data is written. The chunks holding the first write after a seek
+// forward, where the zip writer starts a new entry, stay in memory until the
+// next one; all others are sealed once written past, so nothing unencrypted
+// reaches `inner` and writing to them again fails. `finish` seals the rest.
+pub struct Encryptor<W> {
+    inner: W,
+    stream: StreamBE32<ChaCha20Poly1305>,
+    // The plaintext of the chunks not sealed yet, by index.
+    open: BTreeMap<u32, Vec<u8>>,
+    // The first chunk kept for the entry being written.
+    anchor: u32,
+    // Whether the last seek that moved went forward.
+    moved_forward: bool,
+    // The plaintext size.
+    len: u64,
+    pos: u64,
+}
+
+impl<W: Write + Seek> Encryptor<W> {
+    pub fn new(key: &Key, mut inner: W) -> io::Result<Self> {
+        let mut prefix = [0; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&prefix)?;
+        Ok(Self {
+            inner,
+            stream: key.stream(&prefix),
+            open: BTreeMap::new(),
+            anchor: 0,
+            moved_forward: false,
+            len: 0,
+            pos: 0,
+        })
+    }
+
+    // Seals the chunks still open and returns `inner`.
+    pub fn finish(mut self) -> io::Result<W> {
+        // Every chunk but the last is full, so an input that ends on a chunk
+        // boundary is followed by an empty last chunk.
+        let last = chunk_index(self.len)?;
+        let mut open = std::mem::take(&mut self.open);
+        let tail = open.remove(&last).unwrap_or_default();
+        for (index, chunk) in open {
+            self.seal(index, chunk, false)?;
+        }
+        self.seal(last, tail, true)?;
+        Ok(self.inner)
+    }
+
+    fn seal(&mut self, index: u32, mut chunk: Vec<u8>, last: bool) -> io::Result<()> {
+        self.stream
+            .encrypt_in_place(index, last, b"", &mut chunk)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.inner.seek(SeekFrom::Start(
+            HEADER_LEN + index as u64 * SEALED_CHUNK_SIZE as u64,
+        ))?;
+        self.inner.write_all(&chunk)
+    }
+}
+
+impl<W: Write + Seek> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot write past the end",
+            ));
+        }
+        let index = chunk_index(self.pos)?;
+        if std::mem::take(&mut self.moved_forward) {
+            // A new entry starts here, so the chunks before it are final.
+            self.anchor = index;
+            while let Some(entry) = self.open.first_entry() {
+                if *entry.key() >= index {
+                    break;
+                }
+                let (index, chunk) = entry.remove_entry();
+                self.seal(index, chunk, false)?;
+            }
+        }
+        let exists = index as u64 * (CHUNK_SIZE as u64) < self.len;
+        let chunk = match self.open.entry(index) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if !exists => entry.insert(Vec::with_capacity(CHUNK_SIZE)),
+            Entry::Vacant(_) => return Err(io::Error::other("the chunk was already sealed")),
+        };
+        let offset = (self.pos % CHUNK_SIZE as u64) as usize;
+        let n = buf.len().min(CHUNK_SIZE - offset);
+        if chunk.len() < offset + n {
+            chunk.resize(offset + n, 0);
+        }
+        chunk[offset..offset + n].copy_from_slice(&buf[..n]);
+        let full = chunk.len() == CHUNK_SIZE;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        // Past the chunks kept for the entry, a full chunk is final.
+        if full && offset + n == CHUNK_SIZE && index > self.anchor.saturating_add(1) {
+            let chunk = self
+                .open
+                .remove(&index)
+                .expect("the chunk was just written");
+            self.seal(index, chunk, false)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for Encryptor<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+        if target != self.pos {
+            self.moved_forward = target > self.pos;
+        }
+        self.pos = target;
+        Ok(self.pos)
+    }
+}
+
+fn chunk_index(pos: u64) -> io::Result<u32> {
+    u32::try_from(pos / CHUNK_SIZE as u64).map_err(|_| io::Error::other("too large to encrypt"))
+}
+
+// A backup archive opened for reading, decrypted on the fly if it is
+// encrypted.
+pub enum BackupFile {
+    Plain(File),
+    Encrypted(Decryptor<File>),
+}
+
+// Opens the archive at `path`. Encrypted archives need `key`; with a key,
+// archives that are not encrypted are refused unless the key allows them.
+pub fn open(path: &Path, key: Option<&Key>) -> BotResult<BackupFile> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; MAGIC.len()];
+    let encrypted = match file.read_exact(&mut magic) {
+        Ok(()) => &magic == MAGIC,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    file.rewind()?;
+    if !encrypted {
+        return match key {
+            Some(key) if !key.allow_unencrypted => Err(EncryptionError::NotEncrypted.into()),
+            _ => Ok(BackupFile::Plain(file)),
+        };
+    }
+    let key = key.ok_or(EncryptionError::KeyRequired)?;
+    Ok(BackupFile::Encrypted(Decryptor::new(key, file)?))
+}
+
+impl BackupFile {
+    // Decrypts the whole archive once, so it is known to be intact before
+    // anything is done with its contents. Plain archives are left to the CRCs.
+    pub fn authenticate(&mut self) -> BotResult<()> {
+        if let BackupFile::Encrypted(decryptor) = self {
+            for index in 0..decryptor.chunks {
+                decryptor.load(index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for BackupFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BackupFile::Plain(file) => file.read(buf),
+            BackupFile::Encrypted(decryptor) => decryptor.read(buf),
+        }
+    }
+}
+
+impl Seek for BackupFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            BackupFile::Plain(file) => file.seek(pos),
+            BackupFile::Encrypted(decryptor) => decryptor.seek(pos),
+        }
+    }
+}
+
+// Reads the plaintext of an encrypted archive, one chunk at a time.
+pub struct Decryptor<R> {
+    inner: R,
+    stream: StreamBE32<ChaCha20Poly1305>,
+    chunks: u32,
+    // The sealed size of the last chunk; the others are full.
+    last_len: usize,
+    // The plaintext size.
+    len: u64,
+    pos: u64,
+    // The index and plaintext of the chunk read last.
+    chunk: Option<(u32, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> Decryptor<R> {
+    fn new(key: &Key, mut inner: R) -> io::Result<Self> {
+        let mut header = [0; HEADER_LEN as usize];
+        inner.rewind()?;
+        inner.read_exact(&mut header).map_err(|_| rejected())?;
+        let body = inner.seek(SeekFrom::End(0))? - HEADER_LEN;
+        let sealed = SEALED_CHUNK_SIZE as u64;
+        let chunks = body.div_ceil(sealed).max(1);
+        let last_len = body + sealed - chunks * sealed;
+        // Anything else was cut off or padded.
+        if !(TAG_LEN as u64..sealed).contains(&last_len) {
+            return Err(rejected());
+        }
+        Ok(Self {
+            inner,
+            stream: key.stream(&header[MAGIC.len()..]),
+            chunks: u32::try_from(chunks).map_err(|_| rejected())?,
+            last_len: last_len as usize,
+            len: body - chunks * TAG_LEN as u64,
+            pos: 0,
+            chunk: None,
+        })
+    }
+
+    fn load(&mut self, index: u32) -> io::Result<()> {
+        if self.chunk.as_ref().is_some_and(|(i, _)| *i == index) {
+            return Ok(());
+        }
+        let last = index + 1 == self.chunks;
+        let size = if last {
+            self.last_len
+        } else {
+            SEALED_CHUNK_SIZE
+        };
+        let mut buffer = self.chunk.take().map(|(_, b)| b).unwrap_or_default();
+        buffer.resize(size, 0);
+        self.inner.seek(SeekFrom::Start(
+            HEADER_LEN + index as u64 * SEALED_CHUNK_SIZE as u64,
+        ))?;
+        self.inner.read_exact(&mut buffer)?;
+        self.stream
+            .decrypt_in_place(index, last, b"", &mut buffer)
+            .map_err(|_| rejected())?;
+        self.chunk = Some((index, buffer));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = (self.pos / CHUNK_SIZE as u64) as u32;
+        self.load(index)?;
+        let plaintext = &self.chunk.as_ref().unwrap().1;
+        let offset = (self.pos % CHUNK_SIZE as u64) as usize;
+        let n = buf.len().min(plaintext.len() - offset);
+        buf[..n].copy_from_slice(&plaintext[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for Decryptor<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::BotError;
+
+    fn key(byte: u8) -> Key {
+        Key::parse(&hex::encode([byte; 32])).unwrap()
+    }
+
+    fn sealed(plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = Encryptor::new(&key(1), io::Cursor::new(Vec::new())).unwrap();
+        encryptor.write_all(plaintext).unwrap();
+        encryptor.finish().unwrap().into_inner()
+    }
+
+    fn decrypted(key: &Key, sealed: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut decryptor = Decryptor::new(key, io::Cursor::new(sealed))?;
+        let mut plaintext = Vec::new();
+        decryptor.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trips_and_seeks() {
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 3 + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+            let sealed = sealed(&plaintext);
+            assert_eq!(decrypted(&key(1), sealed.clone()).unwrap(), plaintext);
+            if len > CHUNK_SIZE {
+                let mut decryptor = Decryptor::new(&key(1), io::Cursor::new(sealed)).unwrap();
+                let mut tail = Vec::new();
+                decryptor.seek(SeekFrom::End(-10)).unwrap();
+                decryptor.read_to_end(&mut tail).unwrap();
+                assert_eq!(tail, plaintext[len - 10..]);
+            }
+        }
+    }
+
+    #[test]
+    fn patches_the_entry_being_written_and_nothing_before() {
+        let mut encryptor = Encryptor::new(&key(1), io::Cursor::new(Vec::new())).unwrap();
+        let mut plaintext = vec![1; CHUNK_SIZE * 3 + 10];
+        encryptor.write_all(&plaintext).unwrap();
+        // Like the zip writer finishing an entry: patch its header, go on.
+        encryptor.seek(SeekFrom::Start(14)).unwrap();
+        encryptor.write_all(&[2; 12]).unwrap();
+        plaintext[14..26].fill(2);
+        encryptor.seek(SeekFrom::End(0)).unwrap();
+        encryptor.write_all(&[3; 100]).unwrap();
+        plaintext.extend([3; 100]);
+
+        // Once the next entry has started, the last one is sealed.
+        encryptor.seek(SeekFrom::Start(14)).unwrap();
+        assert!(encryptor.write_all(&[4]).is_err());
+        let sealed = encryptor.finish().unwrap().into_inner();
+        assert_eq!(decrypted(&key(1), sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn rejects_tampering_truncation_and_other_keys() {
+        let plaintext = vec![7; CHUNK_SIZE * 2 + 100];
+        let sealed = sealed(&plaintext);
+        let is_rejected = |result: io::Result<Vec<u8>>| {
+            result
+                .err()
+                .and_then(|e| EncryptionError::from_io(&e))
+                .is_some_and(|e| e == EncryptionError::Rejected)
+        };
+
+        assert!(is_rejected(decrypted(&key(2), sealed.clone())));
+        let mut flipped = sealed.clone();
+        flipped[HEADER_LEN as usize + CHUNK_SIZE + 3] ^= 1;
+        assert!(is_rejected(decrypted(&key(1), flipped)));
+        // Cut at a chunk boundary, the last remaining chunk is not marked last.
+        let cut = HEADER_LEN as usize + SEALED_CHUNK_SIZE * 2;
+        assert!(is_rejected(decrypted(&key(1), sealed[..cut].to_vec())));
+        assert!(is_rejected(decrypted(&key(1), sealed[..cut + 20].to_vec())));
+        assert!(Key::parse("abcd").is_none());
+    }
+
+    #[test]
+    fn refuses_unencrypted_archives_unless_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.zip");
+        std::fs::write(&path, b"PK\x05\x06").unwrap();
+
+        assert!(matches!(open(&path, None), Ok(BackupFile::Plain(_))));
+        assert!(matches!(
+            open(&path, Some(&key(1))),
+            Err(BotError::Encryption(EncryptionError::NotEncrypted))
+        ));
+        let allowing = Key {
+            allow_unencrypted: true,
+            ..key(1)
+        };
+        assert!(matches!(
+            open(&path, Some(&allowing)),
+            Ok(BackupFile::Plain(_))
+        ));
+    }
+}
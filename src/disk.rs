@@ -0,0 +1,142 @@
+use std::io;
+use std::path::Path;
+
+use tracing::{info, warn};
+
+use crate::config::BackupConfig;
+use crate::error::{BotError, BotResult};
+use crate::manifest::BackupInfo;
+use crate::retention::{self, BackupEntry};
+use crate::service::Notice;
+
+// Room for zip headers and the manifest besides the save data itself.
+const ARCHIVE_OVERHEAD: u64 = 1024 * 1024;
+
+// The free space on the volume holding `path`, as far as the bot may use it.
+pub fn available(path: &Path) -> io::Result<u64> {
+    fs2::available_space(path)
+}
+
+// The most space a backup of `total` bytes of save data takes. Compression can
+// grow data that does not compress by a little, and encryption adds a tag to
+// every 64 KiB, so an archive is at worst slightly larger than the save data.
+// Snapshots only store new chunks, but all of them may be new.
+pub fn estimate(total: u64) -> u64 {
+    total + total / 100 + ARCHIVE_OVERHEAD
+}
+
+// Makes sure `needed` bytes are free in `backup_dir` before the backup `name`
+// is written. If they are not, the backups `policy` would prune once the new
+// one exists are pruned now instead of after it. Without a policy, or when
+// that is not enough, the backup is refused. `available` measures free space.
+pub fn make_room(
+    policy: Option<&BackupConfig>,
+    backup_dir: &Path,
+    name: &str,
+    needed: u64,
+    available: impl Fn(&Path) -> io::Result<u64>,
+) -> BotResult<()> {
+    let mut free = available(backup_dir)?;
+    if free >= needed {
+        return Ok(());
+    }
+    if let Some(policy) = policy {
+        let mut entries = retention::scan(backup_dir)?;
+        entries.insert(
+            0,
+            BackupEntry {
+                name: name.to_string(),
+                path: backup_dir.join(name),
+                time: chrono::Local::now().naive_local(),
+                size: needed,
+                snapshot: false,
+                info: BackupInfo::default(),
+                label: None,
+                pinned: false,
+            },
+        );
+        let prune: Vec<_> = retention::plan(policy, entries)
+            .prune
+            .into_iter()
+            .filter(|e| e.name != name)
+            .collect();
+        if !prune.is_empty() {
+            info!(
+                needed,
+                free,
+                backups = prune.len(),
+                "pruning ahead of a backup to make room"
+            );
+            retention::remove(backup_dir, &prune)?;
+            free = available(backup_dir)?;
+        }
+    }
+    if free >= needed {
+        return Ok(());
+    }
+    warn!(needed, free, "not enough free space for a backup");
+    Err(BotError::Precondition(Notice::InsufficientSpace {
+        needed,
+        available: free,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::NAME_FORMAT;
+    use chrono::{Duration, Local};
+
+    #[test]
+    fn prunes_ahead_only_what_the_policy_would_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Local::now().naive_local();
+        let names: Vec<String> = (1..=3)
+            .map(|hours| {
+                (now - Duration::hours(hours))
+                    .format(NAME_FORMAT)
+                    .to_string()
+            })
+            .collect();
+        for name in &names {
+            std::fs::write(dir.path().join(format!("{}.zip", name)), [0; 1000]).unwrap();
+        }
+        // A volume of 4000 bytes holding only the backups.
+        let volume = |path: &Path| -> io::Result<u64> {
+            let used: u64 = std::fs::read_dir(path)?
+                .map(|e| e.unwrap().metadata().unwrap().len())
+                .sum();
+            Ok(4000 - used)
+        };
+        let policy = BackupConfig {
+            keep: 3,
+            ..Default::default()
+        };
+        let incoming = now.format(NAME_FORMAT).to_string();
+
+        make_room(Some(&policy), dir.path(), &incoming, 800, volume).unwrap();
+        assert_eq!(retention::scan(dir.path()).unwrap().len(), 3);
+
+        // The oldest backup would go once the new one is kept, so it goes now.
+        make_room(Some(&policy), dir.path(), &incoming, 1500, volume).unwrap();
+        let left: Vec<_> = retention::scan(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(left, names[..2]);
+
+        // Kept backups are never pruned for space, and nothing is without a
+        // policy.
+        for policy in [Some(&policy), None] {
+            assert!(matches!(
+                make_room(policy, dir.path(), &incoming, 3000, volume),
+                Err(BotError::Precondition(Notice::InsufficientSpace {
+                    needed: 3000,
+                    available: 2000,
+                }))
+            ));
+        }
+        assert_eq!(retention::scan(dir.path()).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use zip::result::ZipError;
+
+use crate::ark_command::ResponseError;
+use crate::config::ConfigError;
+use crate::crypto::EncryptionError;
+use crate::rcon_client::CommandError;
+use crate::s3::S3Error;
+use crate::service::Notice;
+
+pub type BotResult<T> = Result<T, BotError>;
+
+// Every way a command can fail. Commands propagate it with `?`, and the `after`
+// hook turns it into a reply for the user and a log entry for the operator.
+#[derive(Debug)]
+pub enum BotError {
+    Rcon(CommandError),
+    Io(std::io::Error),
+    Zip(ZipError),
+    Config(ConfigError),
+    // A request to an S3-compatible replica failed.
+    S3(S3Error),
+    // An encrypted backup could not be read.
+    Encryption(EncryptionError),
+    // A helper script could not be started.
+    Process {
+        script: PathBuf,
+        source: std::io::Error,
+    },
+    // Something the user named (a backup, for instance) does not exist.
+    NotFound(String),
+    // The command cannot run in the current state; the notice says why.
+    Precondition(Notice),
+    // The chat frontend could not deliver a message.
+    Frontend(Box<dyn std::error::Error + Send + Sync>),
+    // The failure of a backup that several requests were waiting on.
+    Shared(Arc<BotError>),
+}
+
+impl BotError {
+    // Short name used in log entries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BotError::Rcon(_) => "rcon",
+            BotError::Io(_) => "io",
+            BotError::Zip(_) => "zip",
+            BotError::Config(_) => "config",
+            BotError::S3(_) => "s3",
+            BotError::Encryption(_) => "encryption",
+            BotError::Process { .. } => "process",
+            BotError::NotFound(_) => "not_found",
+            BotError::Precondition(_) => "precondition",
+            BotError::Frontend(_) => "frontend",
+            BotError::Shared(e) => e.kind(),
+        }
+    }
+
+    // True for errors caused by the request rather than by the bot or the host.
+    pub fn is_user_error(&self) -> bool {
+        match self {
+            BotError::NotFound(_) | BotError::Precondition(_) => true,
+            BotError::Shared(e) => e.is_user_error(),
+            _ => false,
+        }
+    }
+
+    // The reply shown to the user who ran the command.
+    pub fn user_message(&self) -> String {
+        match self {
+            BotError::Rcon(CommandError::Rcon(rcon::Error::Auth)) => {
+                "RCONの認証に失敗しました．RCONパスワードを確認してください．".to_string()
+            }
+            BotError::Rcon(CommandError::Rcon(rcon::Error::CommandTooLong)) => {
+                "コマンドが長すぎます．".to_string()
+            }
+            BotError::Rcon(CommandError::Rcon(rcon::Error::Io(_))) => "ARKサーバーに接続できませんでした．*/check_server*でサーバーの状態を確認してください．".to_string(),
+            BotError::Rcon(CommandError::Response(ResponseError::Empty)) => {
+                "ARKサーバーから応答がありませんでした．".to_string()
+            }
+            BotError::Rcon(CommandError::Response(ResponseError::Unexpected { .. })) => {
+                "ARKサーバーから想定外の応答がありました．".to_string()
+            }
+            BotError::Io(_) => "ファイルの操作に失敗しました．".to_string(),
+            BotError::Zip(_) => "バックアップファイルの読み書きに失敗しました．".to_string(),
+            BotError::Config(_) => "設定ファイルの読み込みに失敗しました．".to_string(),
+            BotError::S3(_) => "バックアップの転送先との通信に失敗しました．".to_string(),
+            BotError::Encryption(EncryptionError::KeyRequired) => {
+                "バックアップが暗号化されていますが，鍵が設定されていません．".to_string()
+            }
+            BotError::Encryption(EncryptionError::InvalidKey) => {
+                "バックアップの暗号化の鍵が正しくありません．".to_string()
+            }
+            BotError::Encryption(EncryptionError::NotEncrypted) => "バックアップが暗号化されていません．暗号化を有効にする前のバックアップを使うには `allow_unencrypted_backups` を設定してください．".to_string(),
+            BotError::Encryption(EncryptionError::Rejected) => "バックアップの認証に失敗しました．改ざんされているか，鍵が違うため使用できません．".to_string(),
+            BotError::Process { script, .. } => format!(
+                "`{}` の実行に失敗しました．再実行してください．",
+                script.display()
+            ),
+            BotError::NotFound(what) => format!("{}が見つかりませんでした．", what),
+            BotError::Precondition(notice) => notice.to_string(),
+            BotError::Frontend(_) => "メッセージの送信に失敗しました．".to_string(),
+            BotError::Shared(e) => e.user_message(),
+        }
+    }
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Rcon(e) => write!(f, "rcon: {}", e),
+            BotError::Io(e) => write!(f, "io: {}", e),
+            BotError::Zip(e) => write!(f, "zip: {}", e),
+            BotError::Config(e) => write!(f, "config: {}", e),
+            BotError::S3(e) => write!(f, "s3: {}", e),
+            BotError::Encryption(e) => write!(f, "encryption: {}", e),
+            BotError::Process { script, source } => {
+                write!(f, "could not run {}: {}", script.display(), source)
+            }
+            BotError::NotFound(what) => write!(f, "not found: {}", what),
+            BotError::Precondition(notice) => write!(f, "precondition failed: {:?}", notice),
+            BotError::Frontend(e) => write!(f, "frontend: {}", e),
+            BotError::Shared(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+impl From<CommandError> for BotError {
+    fn from(e: CommandError) -> Self {
+        BotError::Rcon(e)
+    }
+}
+
+// Decryption failures surface as I/O errors of the reader they happen in, on
+// their own or inside a zip error.
+impl From<std::io::Error> for BotError {
+    fn from(e: std::io::Error) -> Self {
+        match EncryptionError::from_io(&e) {
+            Some(why) => BotError::Encryption(why),
+            None => BotError::Io(e),
+        }
+    }
+}
+
+impl From<ZipError> for BotError {
+    fn from(e: ZipError) -> Self {
+        match &e {
+            ZipError::Io(io) => match EncryptionError::from_io(io) {
+                Some(why) => BotError::Encryption(why),
+                None => BotError::Zip(e),
+            },
+            _ => BotError::Zip(e),
+        }
+    }
+}
+
+impl From<ConfigError> for BotError {
+    fn from(e: ConfigError) -> Self {
+        BotError::Config(e)
+    }
+}
+
+impl From<S3Error> for BotError {
+    fn from(e: S3Error) -> Self {
+        BotError::S3(e)
+    }
+}
+
+impl From<EncryptionError> for BotError {
+    fn from(e: EncryptionError) -> Self {
+        BotError::Encryption(e)
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backup;
+use crate::error::BotResult;
+
+// Labels and pins live in one file per backup directory, keyed by backup
+// name, since zip archives and snapshot manifests are never rewritten.
+const LABELS_FILE: &str = "labels.json";
+
+// Serialises read-modify-write cycles on the labels files.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupLabel {
+    pub label: Option<String>,
+    // Pinned backups are never pruned.
+    pub pinned: bool,
+}
+
+pub fn load(backup_dir: &Path) -> BotResult<BTreeMap<String, BackupLabel>> {
+    let path = backup_dir.join(LABELS_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let raw = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&raw).map_err(std::io::Error::from)?)
+}
+
+pub fn set_label(backup_dir: &Path, name: &str, label: &str) -> BotResult<()> {
+    update(backup_dir, |labels| {
+        labels.entry(name.to_string()).or_default().label = Some(label.to_string());
+    })
+}
+
+pub fn set_pinned(backup_dir: &Path, name: &str, pinned: bool) -> BotResult<()> {
+    update(backup_dir, |labels| {
+        labels.entry(name.to_string()).or_default().pinned = pinned;
+    })
+}
+
+// Drops what is recorded for backups that no longer exist.
+pub fn forget<'a>(backup_dir: &Path, names: impl IntoIterator<Item = &'a str>) -> BotResult<()> {
+    let names: Vec<_> = names.into_iter().collect();
+    update(backup_dir, |labels| {
+        for name in names {
+            labels.remove(name);
+        }
+    })
+}
+
+fn update(
+    backup_dir: &Path,
+    change: impl FnOnce(&mut BTreeMap<String, BackupLabel>),
+) -> BotResult<()> {
+    let _locked = LOCK.lock().unwrap();
+    let mut labels = load(backup_dir)?;
+    let before = labels.clone();
+    change(&mut labels);
+    labels.retain(|_, l| *l != BackupLabel::default());
+    if labels == before {
+        return Ok(());
+    }
+    let path = backup_dir.join(LABELS_FILE);
+    let partial = backup::partial_path(&path);
+    let raw = serde_json::to_vec_pretty(&labels).map_err(std::io::Error::from)?;
+    std::fs::write(&partial, raw)?;
+    std::fs::rename(&partial, &path)?;
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use crate::config::LogConfig;
+
+const LOG_FILE_PREFIX: &str = "fuwa_ark_bot.log";
+
+// Logs human-readable lines to stdout and JSON lines to a file rotated daily,
+// so incidents can be investigated after the fact. The returned guard flushes
+// the file writer when dropped and has to be kept alive until exit.
+pub fn init(config: &LogConfig) -> WorkerGuard {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let appender = tracing_appender::rolling::daily(&config.dir, LOG_FILE_PREFIX);
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(
+            fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(writer),
+        )
+        .init();
+    guard
+}
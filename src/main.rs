@@ -1,30 +1,51 @@
+mod ark_command;
+mod backup;
+mod catalog;
+mod config;
+mod coordinator;
+mod crypto;
+mod disk;
+mod error;
+mod labels;
+mod logging;
+mod manifest;
+mod rcon_client;
+mod replication;
+mod retention;
+mod s3;
+mod save_rules;
+mod scheduler;
+mod server_status;
+mod service;
+mod snapshot;
+#[cfg(test)]
+mod test_support;
+mod verify;
+
 use std::collections::{HashMap, HashSet};
-use std::io::{copy, Read, Write};
 use std::sync::Arc;
+use std::time::Instant;
 
 use serenity::async_trait;
 use serenity::client::bridge::gateway::ShardManager;
-use serenity::framework::standard::macros::{command, group, help, hook};
+use serenity::framework::standard::macros::{check, command, group, help, hook};
 use serenity::framework::standard::{
-    help_commands, Args, CommandGroup, CommandResult, DispatchError, HelpOptions, StandardFramework,
+    help_commands, Args, CommandGroup, CommandOptions, CommandResult, DispatchError, HelpOptions,
+    Reason, StandardFramework,
 };
 
-use rcon::{AsyncStdStream, Connection, Error};
 use serenity::http::Http;
 use serenity::model::channel::Message;
 use serenity::model::gateway::{GatewayIntents, Ready};
-use serenity::model::id::UserId;
+use serenity::model::id::{ChannelId, MessageId, UserId};
 use serenity::prelude::*;
 use serenity::utils::{content_safe, ContentSafeOptions};
-use std::fs::File;
-use tokio::process::Command;
 use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
-use walkdir::WalkDir;
-use zip::write::FileOptions;
+use tracing::{error, info, info_span, warn, Instrument, Span};
 
-const BACKUP_DIR_PATH: &str = "C:/asmdata/akhBackups";
-const ARK_SAVEDATA_PATH: &str = "C:/asmdata/Servers/Server2/ShooterGame/Saved/SavedArks";
+use config::{Config, CONFIG_PATH};
+use error::{BotError, BotResult};
+use service::{ArkService, Frontend, Notice};
 
 // A container type is created for inserting into the Client's `data`, which
 // allows for data to be accessible across all events and framework commands, or
@@ -35,17 +56,31 @@ impl TypeMapKey for ShardManagerContainer {
     type Value = Arc<Mutex<ShardManager>>;
 }
 
+struct ServiceContainer;
+
+impl TypeMapKey for ServiceContainer {
+    type Value = Arc<ArkService>;
+}
+
 struct CommandCounter;
 
 impl TypeMapKey for CommandCounter {
     type Value = HashMap<String, u64>;
 }
+
+// The span of each running command, opened in `before` and closed in `after`.
+struct CommandSpans;
+
+impl TypeMapKey for CommandSpans {
+    type Value = HashMap<MessageId, (Span, Instant)>;
+}
+
 struct Handler;
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, _: Context, ready: Ready) {
-        println!("{} is connected!", ready.user.name);
+        info!(user = %ready.user.name, "connected to Discord");
     }
 }
 
@@ -55,12 +90,19 @@ impl EventHandler for Handler {
     listplayers,
     save,
     listbackups,
+    prune_backups,
+    pin_backup,
+    unpin_backup,
+    benchmark_backup,
+    verify_backup,
+    schedule,
     rollback,
     check_connection,
     reload_connection,
     check_server,
     start_server,
-    shutdown_server
+    shutdown_server,
+    restart_server
 )]
 struct General;
 
@@ -96,12 +138,109 @@ async fn my_help(
     Ok(())
 }
 
+async fn get_service(ctx: &Context) -> Arc<ArkService> {
+    let data = ctx.data.read().await;
+    data.get::<ServiceContainer>()
+        .expect("Expected ServiceContainer in TypeMap.")
+        .clone()
+}
+
+// Delivers service notices as replies to the message that ran the command.
+struct DiscordFrontend<'a> {
+    ctx: &'a Context,
+    msg: &'a Message,
+    span: Span,
+}
+
+impl<'a> DiscordFrontend<'a> {
+    async fn new(ctx: &'a Context, msg: &'a Message) -> DiscordFrontend<'a> {
+        let data = ctx.data.read().await;
+        let span = data
+            .get::<CommandSpans>()
+            .and_then(|spans| spans.get(&msg.id))
+            .map_or_else(Span::none, |(span, _)| span.clone());
+        Self { ctx, msg, span }
+    }
+
+    // The span service calls made for this command are recorded under.
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+#[async_trait]
+impl Frontend for DiscordFrontend<'_> {
+    async fn notify(&self, notice: Notice) -> BotResult<()> {
+        self.msg
+            .reply(&self.ctx.http, notice.to_string())
+            .await
+            .map_err(|e| BotError::Frontend(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+// Posts notices nobody asked for, such as disk space alerts, to a channel.
+struct ChannelFrontend {
+    http: Arc<Http>,
+    channel: ChannelId,
+}
+
+#[async_trait]
+impl Frontend for ChannelFrontend {
+    async fn notify(&self, notice: Notice) -> BotResult<()> {
+        self.channel
+            .say(&self.http, notice.to_string())
+            .await
+            .map_err(|e| BotError::Frontend(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+// Replaces `allowed_roles`, which only accepts role names known at compile time.
+#[check]
+#[name = "Admin"]
+async fn admin_check(
+    ctx: &Context,
+    msg: &Message,
+    _: &mut Args,
+    _: &CommandOptions,
+) -> Result<(), Reason> {
+    let service = get_service(ctx).await;
+    let config = service.config();
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => {
+            return Err(Reason::User(
+                "This command is only available in a guild.".into(),
+            ))
+        }
+    };
+    let member = msg.member(ctx).await.map_err(|_| Reason::Unknown)?;
+    let is_admin = guild
+        .roles
+        .values()
+        .any(|role| role.name == config.discord.admin_role && member.roles.contains(&role.id));
+    if is_admin {
+        Ok(())
+    } else {
+        Err(Reason::User(format!(
+            "This command requires the `{}` role.",
+            config.discord.admin_role
+        )))
+    }
+}
+
 #[hook]
 async fn before(ctx: &Context, msg: &Message, command_name: &str) -> bool {
-    println!(
-        "Got command '{}' by user '{}'",
-        command_name, msg.author.name
+    let span = info_span!(
+        "command",
+        command = command_name,
+        user = %msg.author.name,
+        user_id = msg.author.id.0,
+        guild = msg.guild_id.map(|id| id.0),
+        duration_ms = tracing::field::Empty,
     );
+    span.in_scope(|| info!("command started"));
 
     // Increment the number of times this command has been run once. If
     // the command's name does not exist in the counter, add a default
@@ -112,26 +251,62 @@ async fn before(ctx: &Context, msg: &Message, command_name: &str) -> bool {
         .expect("Expected CommandCounter in TypeMap.");
     let entry = counter.entry(command_name.to_string()).or_insert(0);
     *entry += 1;
+    data.get_mut::<CommandSpans>()
+        .expect("Expected CommandSpans in TypeMap.")
+        .insert(msg.id, (span, Instant::now()));
 
     true // if `before` returns false, command processing doesn't happen.
 }
 
 #[hook]
-async fn after(_ctx: &Context, _msg: &Message, command_name: &str, command_result: CommandResult) {
-    match command_result {
-        Ok(()) => println!("Processed command '{}'", command_name),
-        Err(why) => println!("Command '{}' returned error {:?}", command_name, why),
-    }
-}
+async fn after(ctx: &Context, msg: &Message, command_name: &str, command_result: CommandResult) {
+    let (span, started) = {
+        let mut data = ctx.data.write().await;
+        data.get_mut::<CommandSpans>()
+            .and_then(|spans| spans.remove(&msg.id))
+            .unwrap_or_else(|| (Span::none(), Instant::now()))
+    };
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    let _entered = span.enter();
 
-#[hook]
-async fn unknown_command(_ctx: &Context, _msg: &Message, unknown_command_name: &str) {
-    println!("Could not find command named '{}'", unknown_command_name);
+    let why = match command_result {
+        Ok(()) => {
+            info!("command finished");
+            return;
+        }
+        Err(why) => why,
+    };
+    let reply = match why.downcast_ref::<BotError>() {
+        Some(error) => {
+            if error.is_user_error() {
+                warn!(kind = error.kind(), error = %error, "command rejected");
+            } else {
+                error!(kind = error.kind(), error = %error, "command failed");
+            }
+            // There is no point in replying when replying is what failed.
+            if let BotError::Frontend(_) = error {
+                return;
+            }
+            error.user_message()
+        }
+        None => {
+            error!(kind = "unknown", error = %why, "command failed");
+            "コマンドの実行中にエラーが発生しました．".to_string()
+        }
+    };
+    drop(_entered);
+    if let Err(why) = msg.reply(&ctx.http, reply).instrument(span.clone()).await {
+        span.in_scope(|| error!(command = command_name, error = %why, "could not reply"));
+    }
 }
 
 #[hook]
-async fn normal_message(_ctx: &Context, msg: &Message) {
-    println!("Message is not a command '{}'", msg.content);
+async fn unknown_command(_ctx: &Context, msg: &Message, unknown_command_name: &str) {
+    info!(
+        command = unknown_command_name,
+        user = %msg.author.name,
+        "unknown command"
+    );
 }
 
 #[hook]
@@ -157,22 +332,19 @@ async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, _com
 }
 
 #[tokio::main]
-async fn main() {
-    let token = std::fs::read_to_string("discord_token").expect("could not read DISCORD_TOKEN");
+async fn main() -> BotResult<()> {
+    let token = std::fs::read_to_string("discord_token")?;
+    let config = Arc::new(Config::load(CONFIG_PATH)?);
+    let _log_guard = logging::init(&config.log);
 
-    std::thread::spawn(|| loop {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(3600000));
-        rt.block_on(async {
-            let output = rcon("SaveWorld").await.expect("failed to run `rcon`");
+    let service = Arc::new(ArkService::new(Arc::clone(&config)));
+    service.remove_partial_backups();
+    service.restore_schedule()?;
 
-            if output.is_empty() {
-                println!("backup failed");
-            } else {
-                create_backup().await.expect("failed to create a backup");
-            }
-        });
-    });
+    let schedule_service = Arc::clone(&service);
+    tokio::spawn(async move { schedule_service.run_schedule().await });
+    let replication_service = Arc::clone(&service);
+    tokio::spawn(async move { replication_service.run_replication().await });
 
     let http = Http::new(&token);
 
@@ -197,7 +369,7 @@ async fn main() {
         .configure(|c| {
             c.with_whitespace(true)
                 .on_mention(Some(bot_id))
-                .prefix("/")
+                .prefix(&config.discord.prefix)
                 // In this case, if "," would be first, a message would never
                 // be delimited at ", ", forcing you to trim your arguments if you
                 // want to avoid whitespaces at the start of each.
@@ -209,7 +381,6 @@ async fn main() {
         .before(before)
         .after(after)
         .unrecognised_command(unknown_command)
-        .normal_message(normal_message)
         .on_dispatch_error(dispatch_error)
         .help(&MY_HELP)
         .group(&GENERAL_GROUP);
@@ -222,6 +393,8 @@ async fn main() {
         .event_handler(Handler)
         .framework(framework)
         .type_map_insert::<CommandCounter>(HashMap::default())
+        .type_map_insert::<CommandSpans>(HashMap::default())
+        .type_map_insert::<ServiceContainer>(Arc::clone(&service))
         .await
         .expect("Err creating client");
 
@@ -230,9 +403,20 @@ async fn main() {
         data.insert::<ShardManagerContainer>(Arc::clone(&client.shard_manager));
     }
 
+    let alerts = config.discord.admin_channel.map(|id| ChannelFrontend {
+        http: Arc::clone(&client.cache_and_http.http),
+        channel: ChannelId(id),
+    });
+    let disk_service = Arc::clone(&service);
+    tokio::spawn(async move {
+        let alerts = alerts.as_ref().map(|a| a as &dyn Frontend);
+        disk_service.run_disk_watch(alerts).await
+    });
+
     if let Err(why) = client.start().await {
-        println!("Client error: {:?}", why);
+        error!(error = %why, "client error");
     }
+    Ok(())
 }
 
 // say something to Discord channel while ensuring that user and role mentions are replaced with a safe textual alternative.
@@ -272,454 +456,249 @@ async fn say(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 #[command]
 #[description = "ポート公開用ソフト (playit.gg) が動作しているかを確認します"]
 async fn check_connection(ctx: &Context, msg: &Message) -> CommandResult {
-    let raw_output = Command::new("powershell")
-        .arg(r#"scripts/check_connection.ps1"#)
-        .output()
-        .await
-        .expect("failed to start `check_connection`");
-    let output = String::from_utf8_lossy(&raw_output.stdout);
-    let m = if output == "0" {
-        "playit.ggが起動されていません．*/reload_connection*を実行してplayit.ggを起動してください．"
-    } else {
-        "playit.ggは実行中です．回線に問題がある場合は*/reload_connection*を実行してください．"
-    };
-    msg.reply(&ctx.http, m).await?;
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let notice = service
+        .check_connection()
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
-async fn num_listplayers() -> usize {
-    if let Ok(output) = rcon("listplayers").await {
-        output.chars().filter(|x| *x == ',').count()
-    } else {
-        1001001001
-    }
-}
-
 #[command]
-#[allowed_roles("ARK Server Admin")]
+#[checks(Admin)]
 #[description = "ポート公開用ソフト (playit.gg) を再起動します"]
 async fn reload_connection(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    if (num_listplayers().await == 0 || num_listplayers().await == 1001001001)
-        || (!args.is_empty() && args.rest() == "force")
-    {
-        let result = Command::new("powershell")
-            .arg(r#"C:/Users/akh/Documents/ark-playit-restart.ps1"#)
-            .output()
-            .await;
-        if result.is_err() {
-            msg.reply(
-                &ctx.http,
-                "reload_connectionの実行に失敗しました．再実行してください．",
-            )
-            .await?;
-        } else {
-            msg.reply(&ctx.http, "Connection Reloaded").await?;
-        }
-    } else {
-        msg.reply(&ctx.http, "ゲームにプレイヤーが残っていたため，再起動を中止しました．強制再起動をする場合は*/reload_connection force*を実行してください．").await?;
-    }
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let force = args.rest() == "force";
+    let notice = service
+        .reload_connection(force)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
-#[description = "ゲームをセーブします"]
-async fn save(ctx: &Context, msg: &Message) -> CommandResult {
-    let output = rcon("SaveWorld").await.expect("failed to run `rcon`");
-
-    if output.is_empty() {
-        msg.reply(&ctx.http, "No output was returned.").await?;
-    } else {
-        msg.reply(&ctx.http, "セーブとバックアップを開始しました．")
-            .await?;
-        create_backup().await?;
-        msg.reply(&ctx.http, output).await?;
-    };
-    Ok(())
-}
-
-async fn rcon(cmd: &str) -> Result<String, Error> {
-    fn trim_newline(s: &str) -> String {
-        let mut str = s.to_owned();
-        if str.ends_with('\n') {
-            str.pop();
-            if str.ends_with('\r') {
-                str.pop();
-            }
-        }
-        str
-    }
-
-    let pass = trim_newline(
-        &std::fs::read_to_string("rcon_password").expect("could not read RCON Password"),
-    );
-    let mut conn = <Connection<AsyncStdStream>>::builder()
-        .enable_factorio_quirks(true)
-        .connect("127.0.0.1:32330", &pass)
+#[description = "ゲームをセーブします．*label:\"名前\"*を付けるとバックアップに名前を付けます"]
+async fn save(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .save(target, rest, &frontend)
+        .instrument(frontend.span())
         .await?;
-    let resp = conn.cmd(cmd).await?;
-    Ok(resp)
+    frontend.notify(notice).await?;
+    Ok(())
 }
 
 #[command]
 #[description = "ゲーム内に文字列を表示します"]
-#[allowed_roles("ARK Server Admin")]
+#[checks(Admin)]
 async fn broadcast(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    if !args.is_empty() {
-        msg.reply(&ctx.http, &format!("[Broadcast]\n{}", args.rest()))
-            .await?;
-        rcon(&format!("Broadcast {}", args.rest()))
-            .await
-            .expect("failed to run `rcon`");
-    } else {
-        msg.reply(&ctx.http, "An argument is required.").await?;
-    }
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, text) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .broadcast(target, text)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
 #[description = "サーバーを起動します"]
-#[allowed_roles("ARK Server Admin")]
-async fn start_server(ctx: &Context, msg: &Message) -> CommandResult {
-    let output = rcon("listplayers").await.expect("failed to run `rcon`");
-    if output.is_empty() {
-        let raw_output = Command::new("powershell")
-            .arg(r#"scripts/start_ark_server.ps1"#)
-            .output()
-            .await
-            .expect("failed to start `check_connection`");
-        let output = String::from_utf8_lossy(&raw_output.stdout);
-        if output.is_empty() {
-            msg.reply(&ctx.http, "No output was returned.").await?;
-        } else {
-            msg.reply(&ctx.http, output).await?;
-        }
-    } else {
-        msg.reply(&ctx.http, "ARKサーバーは既に動作中です．")
-            .await?;
-    }
+#[checks(Admin)]
+async fn start_server(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, _) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .start_server(target)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
 #[description = "サーバーを再起動します"]
-#[allowed_roles("ARK Server Admin")]
+#[checks(Admin)]
 async fn restart_server(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    if num_listplayers().await == 0 || (!args.is_empty() && args.rest() == "force") {
-        msg.reply(&ctx.http, "ゲームをセーブします．").await?;
-        let mut save_succeeded_flag = false;
-        for i in 0..3 {
-            let output = rcon("SaveWorld").await.expect("failed to run `rcon`");
-
-            if output.is_empty() {
-                if i != 2 {
-                    msg.reply(&ctx.http, "セーブに失敗しました．再試行します．")
-                        .await?;
-                    sleep(Duration::from_millis(1000)).await;
-                } else {
-                    msg.reply(&ctx.http, "セーブに失敗しました．").await?;
-                }
-            } else {
-                msg.reply(&ctx.http, "セーブとバックアップを開始しました．")
-                    .await?;
-                create_backup().await?;
-                msg.reply(&ctx.http, output).await?;
-                save_succeeded_flag = true;
-                break;
-            };
-        }
-        if save_succeeded_flag {
-            msg.reply(&ctx.http, "シャットダウンを開始します．").await?;
-            let output = rcon("DoExit").await.expect("failed to run `rcon`");
-
-            if output.is_empty() {
-                msg.reply(&ctx.http, "シャットダウンが確認できませんでした．*/check_server*などのコマンドを使用してサーバーが正常終了しているかを確認してください．サーバーの起動は*/start_server*で行えます．").await?;
-            } else {
-                msg.reply(&ctx.http, output).await?;
-                let raw_output = Command::new("powershell")
-                    .arg(r#"scripts/start_ark_server.ps1"#)
-                    .output()
-                    .await
-                    .expect("failed to start `check_connection`");
-                let output = String::from_utf8_lossy(&raw_output.stdout);
-                if output.is_empty() {
-                    msg.reply(&ctx.http, "No output was returned.").await?;
-                } else {
-                    msg.reply(&ctx.http, output).await?;
-                }
-            };
-        } else {
-            msg.reply(
-                &ctx.http,
-                "サーバーがコマンドを受け付けていません．シャットダウンを中断します．",
-            )
-            .await?;
-        }
-    } else {
-        msg.reply(&ctx.http, "ゲームにプレイヤーが残っていたため，シャットダウンを中止しました．\n強制シャットダウンをする場合は*/shutdown force*を実行してください．").await?;
-    }
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .shutdown(target, rest == "force", true, &frontend)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
 #[description = "サーバーをセーブしてシャットダウンします"]
-#[allowed_roles("ARK Server Admin")]
+#[checks(Admin)]
 async fn shutdown_server(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    if num_listplayers().await == 0 || (!args.is_empty() && args.rest() == "force") {
-        msg.reply(&ctx.http, "ゲームをセーブします．").await?;
-        let mut save_succeeded_flag = false;
-        for i in 0..3 {
-            let output = rcon("SaveWorld").await.expect("failed to run `rcon`");
-
-            if output.is_empty() {
-                if i != 2 {
-                    msg.reply(&ctx.http, "セーブに失敗しました．再試行します．")
-                        .await?;
-                    sleep(Duration::from_millis(1000)).await;
-                } else {
-                    msg.reply(&ctx.http, "セーブに失敗しました．").await?;
-                }
-            } else {
-                msg.reply(&ctx.http, "セーブとバックアップを開始しました．")
-                    .await?;
-                create_backup().await?;
-                msg.reply(&ctx.http, output).await?;
-                save_succeeded_flag = true;
-                break;
-            };
-        }
-        if save_succeeded_flag {
-            msg.reply(&ctx.http, "シャットダウンを開始します．").await?;
-            let output = rcon("DoExit").await.expect("failed to run `rcon`");
-
-            if output.is_empty() {
-                msg.reply(&ctx.http, "No output was returned.").await?;
-            } else {
-                msg.reply(&ctx.http, output).await?;
-            };
-        } else {
-            msg.reply(
-                &ctx.http,
-                "サーバーがコマンドを受け付けていません．シャットダウンを中断します．",
-            )
-            .await?;
-        }
-    } else {
-        msg.reply(&ctx.http, "ゲームにプレイヤーが残っていたため，シャットダウンを中止しました．強制シャットダウンをする場合は*/shutdown force*を実行してください．").await?;
-    }
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .shutdown(target, rest == "force", false, &frontend)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
 #[description = "ARKサーバーが起動しているかを確認します"]
-async fn check_server(ctx: &Context, msg: &Message) -> CommandResult {
-    let output = rcon("listplayers").await;
-    if output.is_err() || output.unwrap().is_empty() {
-        msg.reply(&ctx.http, "ARKサーバーは動作停止中です．")
-            .await?;
-    } else {
-        msg.reply(&ctx.http, "ARKサーバーは動作中です．").await?;
-    }
+async fn check_server(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, _) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .check_server(target)
+        .instrument(frontend.span())
+        .await;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
-#[description = "ロールバック可能なバックアップリストを表示します"]
-#[allowed_roles("ARK Server Admin")]
-async fn listbackups(ctx: &Context, msg: &Message) -> CommandResult {
-    let mut list: String =
-        String::from("表記説明：\n`2022-12-21_(16-11-21).zip` 2022/12/21 16:11のバックアップ\n\n");
-    let paths = std::fs::read_dir(BACKUP_DIR_PATH)?;
-    for (i, path) in paths.into_iter().enumerate() {
-        list.push_str(&format!(
-            "{}: `{}`\n",
-            i,
-            path?
-                .file_name()
-                .to_str()
-                .expect("failed to get file names")
-        ));
-        //msg.channel_id
-        //   .say(&ctx.http, &path?.file_name().to_str().expect("failed to get file names"))
-        //  .await?;
-    }
-    msg.reply(&ctx.http, list).await?;
+#[description = "ロールバック可能なバックアップリストを表示します．*sort:*，*trigger:*，*from:*，*to:*，*page:*で並べ替えと絞り込みができます"]
+#[checks(Admin)]
+async fn listbackups(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .list_backups(target, rest)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
-#[description = "指定されたセーブデータを使ってロールバックします"]
-#[allowed_roles("ARK Server Admin")]
-async fn rollback(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    // サーバーが起動中かをチェック
-    let output = rcon("listplayers").await;
-    if output.is_err() || output.unwrap().is_empty() {
-        // バックアップファイルが指定されているかをチェック
-        if !args.is_empty() {
-            // forceオプションの有無をチェック
-            if !args.rest().contains("force") {
-                msg.reply(&ctx.http, "ロールバックを開始します．").await?;
-                let zip_fullpath = format!(
-                    "{}/{}.zip",
-                    BACKUP_DIR_PATH,
-                    args.rest()
-                        .strip_prefix("force")
-                        .expect("failed to get the zip fullpath")
-                        .trim()
-                );
-                let fname = std::path::Path::new(&zip_fullpath);
-                let file = File::open(fname).unwrap();
-
-                let mut archive = zip::ZipArchive::new(file).unwrap();
-
-                for i in 0..archive.len() {
-                    let mut file = archive.by_index(i).expect("failed to open zip file");
-                    let outpath = match file.enclosed_name() {
-                        Some(path) => std::path::Path::new(ARK_SAVEDATA_PATH).join(path),
-                        None => continue,
-                    };
-
-                    {
-                        let comment = file.comment();
-                        if !comment.is_empty() {
-                            println!("File {} comment: {}", i, comment);
-                        }
-                    }
-
-                    if (*file.name()).ends_with('/') {
-                        println!("File {} extracted to \"{}\"", i, outpath.display());
-                        std::fs::create_dir_all(&outpath).unwrap();
-                    } else {
-                        println!(
-                            "File {} extracted to \"{}\" ({} bytes)",
-                            i,
-                            outpath.display(),
-                            file.size()
-                        );
-                        if let Some(p) = outpath.parent() {
-                            if !p.exists() {
-                                std::fs::create_dir_all(p).unwrap();
-                            }
-                        }
-                        let mut outfile = File::create(&outpath).unwrap();
-                        copy(&mut file, &mut outfile).unwrap();
-                    }
-                }
-                msg.reply(&ctx.http, "ロールバックを正常に終了しました．")
-                    .await?;
-            } else {
-                msg.reply(&ctx.http, "ロールバックを行うと現在のデータは失われます．確認のため*/rollback force ファイル名*を実行してください．").await?;
-            }
-        } else {
-            msg.reply(&ctx.http, "セーブデータ名を指定してください．利用可能なセーブデータは*/listbackups*で確認できます．").await?;
-        }
-    } else {
-        msg.reply(
-            &ctx.http,
-            "ARKサーバーが動作中です．ロールバックを行う前にサーバーを停止してください．",
-        )
+#[description = "現在のセーブデータを各圧縮方式で圧縮し，サイズと時間を比較します"]
+#[checks(Admin)]
+async fn benchmark_backup(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, _) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .benchmark_backup(target, &frontend)
+        .instrument(frontend.span())
         .await?;
-    }
+    frontend.notify(notice).await?;
     Ok(())
 }
 
-async fn create_backup() -> zip::result::ZipResult<()> {
-    println!("backup started");
-    let date = chrono::Local::now()
-        .format("%Y-%m-%d_(%H-%M-%S)")
-        .to_string();
-    let dest = format!("{}/{}.zip", BACKUP_DIR_PATH, date);
-    let path = std::path::Path::new(&dest);
-    let mut zip = zip::ZipWriter::new(std::fs::File::create(path)?);
-    let options = FileOptions::default().compression_method(zip::CompressionMethod::Bzip2);
-
-    let walkdir = WalkDir::new(ARK_SAVEDATA_PATH);
-    let it = walkdir.into_iter().filter_map(|e| e.ok());
-
-    let mut buffer = Vec::new();
-    for entry in it {
-        let path = entry.path();
-        let name = path
-            .strip_prefix(ARK_SAVEDATA_PATH)
-            .unwrap()
-            .to_str()
-            .unwrap();
-        if path.is_file()
-            && (!(path
-                .extension()
-                .unwrap()
-                .to_str()
-                .expect("failed to get a file extension")
-                .to_string()
-                .contains("bak")
-                || name != "Fjordur.ark"
-                    && name.contains("Fjordur")
-                    && path.extension().unwrap() == "ark"))
-        {
-            println!("Add: {}", name);
-            zip.start_file(name, options)?;
-            let mut f = File::open(path)?;
-
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-            buffer.clear();
-        } else if path.is_dir() {
-            zip.add_directory(name, options)?;
-        }
-    }
-    zip.finish()?;
+#[command]
+#[description = "バックアップを読み込み，マニフェストと照合して破損がないか確認します．*all*で全てのバックアップを確認します"]
+#[checks(Admin)]
+async fn verify_backup(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .verify_backup(target, rest, &frontend)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
+    Ok(())
+}
 
-    // if the num of file is greater than 10, delete the oldest backup
-    if 10
-        < std::fs::read_dir(BACKUP_DIR_PATH)
-            .expect("failed to read the backup directory")
-            .count()
-    {
-        let paths =
-            std::fs::read_dir(BACKUP_DIR_PATH).expect("failed to read the backup directory");
-        let mut old_path = std::path::PathBuf::from(BACKUP_DIR_PATH);
-        let mut old_time = std::time::SystemTime::now();
-
-        for result_path in paths {
-            let entry = result_path.expect("failed to read a file (backup)");
-            let metadata = std::fs::metadata(BACKUP_DIR_PATH)?;
-            let created_time = metadata.created()?;
-
-            if created_time < old_time {
-                old_time = created_time;
-                old_path = entry.path();
-            }
-        }
-        if old_path != std::path::PathBuf::from(BACKUP_DIR_PATH) {
-            std::fs::remove_file(&old_path)?;
-            println!(
-                "Deleted {}",
-                old_path
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .expect("failed to get a file name")
-            );
-        }
-    }
-    println!("backup finished");
+#[command]
+#[description = "定期セーブ・バックアップのスケジュールを管理します．*list*, *add [サーバー名] <cron式>*, *remove <番号>*, *pause <番号>*, *resume <番号>*"]
+#[checks(Admin)]
+async fn schedule(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let notice = frontend
+        .span()
+        .in_scope(|| service.schedule(msg.channel_id.0, args.rest()))?;
+    frontend.notify(notice).await?;
+    Ok(())
+}
+
+#[command]
+#[description = "保持ルールで削除されるバックアップを表示します．*force*を付けると実際に削除します"]
+#[checks(Admin)]
+async fn prune_backups(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let dry_run = rest != "force";
+    let notice = service
+        .prune_backups(target, dry_run)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
+    Ok(())
+}
+
+#[command]
+#[description = "指定されたバックアップを固定し，自動で削除されないようにします"]
+#[checks(Admin)]
+async fn pin_backup(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .pin_backup(target, rest, true)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
+    Ok(())
+}
+
+#[command]
+#[description = "指定されたバックアップの固定を解除します"]
+#[checks(Admin)]
+async fn unpin_backup(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .pin_backup(target, rest, false)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
+    Ok(())
+}
+
+#[command]
+#[description = "指定されたセーブデータを使ってロールバックします．*config*を付けると設定ファイルも戻します"]
+#[checks(Admin)]
+async fn rollback(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, rest) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .rollback(target, rest, &frontend)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
 
 #[command]
 #[description = "オンラインのプレイヤーリストを表示します"]
-#[allowed_roles("ARK Server Admin")]
-async fn listplayers(ctx: &Context, msg: &Message) -> CommandResult {
-    let output = rcon("listplayers").await.expect("failed to run `rcon`");
-    if output.is_empty() {
-        msg.reply(&ctx.http, "Failed to get the player list.")
-            .await?;
-    } else {
-        let mut v = vec![];
-        for li in output.lines() {
-            let splitted = li.split(',').collect::<Vec<&str>>();
-            v.push(splitted[0]);
-        }
-        msg.reply(&ctx.http, v.join("\n")).await?;
-    }
+#[checks(Admin)]
+async fn listplayers(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let service = get_service(ctx).await;
+    let frontend = DiscordFrontend::new(ctx, msg).await;
+    let (target, _) = service.select_server(msg.channel_id.0, args.rest())?;
+    let notice = service
+        .list_players(target)
+        .instrument(frontend.span())
+        .await?;
+    frontend.notify(notice).await?;
     Ok(())
 }
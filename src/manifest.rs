@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+// Name of the manifest inside zip backups. It is not part of the save data and
+// is skipped on restore.
+pub const MANIFEST_ENTRY: &str = ".backup-manifest.json";
+pub const MANIFEST_VERSION: u32 = 1;
+
+// What the server looked like when a backup was taken. Every field is
+// best-effort and missing from backups made before manifests existed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct BackupInfo {
+    pub trigger: Option<Trigger>,
+    pub map: Option<String>,
+    pub server_version: Option<String>,
+    pub players: Option<usize>,
+}
+
+// What a backup was taken for.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    Scheduled,
+    Manual,
+    PreShutdown,
+    PreRollback,
+}
+
+impl Trigger {
+    pub const ALL: [Trigger; 4] = [
+        Trigger::Scheduled,
+        Trigger::Manual,
+        Trigger::PreShutdown,
+        Trigger::PreRollback,
+    ];
+
+    // The name `/listbackups trigger:` takes.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trigger::Scheduled => "scheduled",
+            Trigger::Manual => "manual",
+            Trigger::PreShutdown => "pre-shutdown",
+            Trigger::PreRollback => "pre-rollback",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Trigger::Scheduled => "定期",
+            Trigger::Manual => "手動",
+            Trigger::PreShutdown => "シャットダウン前",
+            Trigger::PreRollback => "ロールバック前",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileRecord {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+// The manifest stored in zip backups.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ZipManifest {
+    pub version: u32,
+    #[serde(flatten)]
+    pub info: BackupInfo,
+    pub files: Vec<FileRecord>,
+}
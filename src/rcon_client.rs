@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rcon::{AsyncStdStream, Connection, Error};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::ark_command::{ArkCommand, ArkResponse, ResponseError};
+use crate::config::{Config, ServerConfig};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// How long after launching a server an unreachable RCON port counts as
+// "starting" rather than "offline".
+const STARTUP_GRACE: Duration = Duration::from_secs(20 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    // No command has been sent yet.
+    Idle,
+    Connected,
+    // The last connection attempt failed; the next one is allowed after `retry_at`.
+    Disconnected { failures: u32, retry_at: Instant },
+    AuthFailed { retry_at: Instant },
+}
+
+struct Inner {
+    conn: Option<Connection<AsyncStdStream>>,
+    // Another handle on the socket of `conn`, to check that the server has not
+    // closed it before a command is written.
+    socket: Option<TcpStream>,
+    failures: u32,
+}
+
+// Keeps one authenticated RCON connection per server. Commands are serialized
+// through the connection lock, and a dropped connection (e.g. after a server
+// restart) is re-established on the next command, with exponential backoff
+// between failed attempts.
+pub struct RconClient {
+    address: String,
+    password_file: PathBuf,
+    inner: Mutex<Inner>,
+    state: RwLock<ConnectionState>,
+    started_at: RwLock<Option<Instant>>,
+}
+
+impl RconClient {
+    pub fn new(server: &ServerConfig) -> Self {
+        Self {
+            address: server.rcon_address.clone(),
+            password_file: server.rcon_password_file.clone(),
+            inner: Mutex::new(Inner {
+                conn: None,
+                socket: None,
+                failures: 0,
+            }),
+            state: RwLock::new(ConnectionState::Idle),
+            started_at: RwLock::new(None),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.read().expect("RCON state lock poisoned")
+    }
+
+    // Called after the bot launches the server process.
+    pub fn mark_starting(&self) {
+        *self.started_at.write().expect("RCON state lock poisoned") = Some(Instant::now());
+    }
+
+    pub fn is_starting(&self) -> bool {
+        self.started_at
+            .read()
+            .expect("RCON state lock poisoned")
+            .is_some_and(|at| at.elapsed() < STARTUP_GRACE)
+    }
+
+    // Lets the next command connect at once, even if the backoff after a failed
+    // attempt has not run out.
+    pub fn retry_now(&self) {
+        let mut state = self.state.write().expect("RCON state lock poisoned");
+        match &mut *state {
+            ConnectionState::Disconnected { retry_at, .. }
+            | ConnectionState::AuthFailed { retry_at } => {
+                *retry_at = Instant::now();
+            }
+            _ => {}
+        }
+    }
+
+    pub async fn run(&self, command: &ArkCommand) -> Result<ArkResponse, CommandError> {
+        let raw = self
+            .cmd(&command.to_rcon())
+            .await
+            .map_err(CommandError::Rcon)?;
+        command.parse_response(&raw).map_err(CommandError::Response)
+    }
+
+    async fn cmd(&self, cmd: &str) -> Result<String, Error> {
+        let mut inner = self.inner.lock().await;
+        // A reused connection may have been closed by a server restart, so it
+        // is replaced if the server hung up. Once written, a command is never
+        // sent again, as the server may already have run it.
+        if inner.socket.as_ref().is_some_and(|socket| !is_open(socket)) {
+            inner.conn = None;
+            inner.socket = None;
+        }
+        self.try_cmd(&mut inner, cmd).await
+    }
+
+    async fn try_cmd(&self, inner: &mut Inner, cmd: &str) -> Result<String, Error> {
+        let conn = self.connection(inner).await?;
+        let result = match timeout(IO_TIMEOUT, conn.cmd(cmd)).await {
+            Ok(result) => result,
+            Err(_) => Err(timed_out()),
+        };
+        if result.is_err() {
+            inner.conn = None;
+            inner.socket = None;
+            self.set_state(ConnectionState::Disconnected {
+                failures: inner.failures,
+                retry_at: Instant::now(),
+            });
+        }
+        result
+    }
+
+    async fn connection<'a>(
+        &self,
+        inner: &'a mut Inner,
+    ) -> Result<&'a mut Connection<AsyncStdStream>, Error> {
+        if inner.conn.is_none() {
+            match self.state() {
+                ConnectionState::Disconnected { retry_at, .. }
+                | ConnectionState::AuthFailed { retry_at }
+                    if Instant::now() < retry_at =>
+                {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "waiting before reconnecting to the RCON server",
+                    )));
+                }
+                _ => {}
+            }
+            match self.connect().await {
+                Ok((conn, socket)) => {
+                    inner.conn = Some(conn);
+                    inner.socket = Some(socket);
+                    inner.failures = 0;
+                    self.set_state(ConnectionState::Connected);
+                    *self.started_at.write().expect("RCON state lock poisoned") = None;
+                }
+                Err(e) => {
+                    self.mark_failed(inner, matches!(e, Error::Auth));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(inner
+            .conn
+            .as_mut()
+            .expect("connection was just established"))
+    }
+
+    async fn connect(&self) -> Result<(Connection<AsyncStdStream>, TcpStream), Error> {
+        let pass = std::fs::read_to_string(&self.password_file)?;
+        let pass = pass.trim_end_matches(['\r', '\n']);
+        let connect = async {
+            let socket = tokio::net::TcpStream::connect(self.address.as_str())
+                .await?
+                .into_std()?;
+            let handle = socket.try_clone()?;
+            let conn = <Connection<AsyncStdStream>>::builder()
+                .enable_factorio_quirks(true)
+                .handshake(AsyncStdStream(socket.into()), pass)
+                .await?;
+            Ok((conn, handle))
+        };
+        match timeout(IO_TIMEOUT, connect).await {
+            Ok(result) => result,
+            Err(_) => Err(timed_out()),
+        }
+    }
+
+    fn mark_failed(&self, inner: &mut Inner, auth: bool) {
+        inner.failures = inner.failures.saturating_add(1);
+        let retry_at = Instant::now() + backoff(inner.failures);
+        self.set_state(if auth {
+            ConnectionState::AuthFailed { retry_at }
+        } else {
+            ConnectionState::Disconnected {
+                failures: inner.failures,
+                retry_at,
+            }
+        });
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.state.write().expect("RCON state lock poisoned") = state;
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    Rcon(Error),
+    Response(ResponseError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Rcon(e) => write!(f, "RCON error: {}", e),
+            CommandError::Response(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+// False once the server has closed `socket` or it has failed. The socket is
+// non-blocking, so this does not wait for data.
+fn is_open(socket: &TcpStream) -> bool {
+    match socket.peek(&mut [0]) {
+        Ok(n) => n > 0,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    }
+}
+
+fn timed_out() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "RCON server did not respond in time",
+    ))
+}
+
+// One client per configured server, keyed by server name.
+pub struct RconClients(HashMap<String, Arc<RconClient>>);
+
+impl RconClients {
+    pub fn new(config: &Config) -> Self {
+        Self(
+            config
+                .servers
+                .iter()
+                .map(|(name, server)| (name.clone(), Arc::new(RconClient::new(server))))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, name: &str) -> Arc<RconClient> {
+        Arc::clone(self.0.get(name).expect("unknown server name"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_rcon::{MockArkServer, Reply};
+
+    #[tokio::test]
+    async fn runs_commands_over_one_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let client = RconClient::new(&mock.server_config(dir.path()));
+
+        let response = client.run(&ArkCommand::SaveWorld).await.unwrap();
+        assert_eq!(response, ArkResponse::WorldSaved);
+        assert_eq!(client.state(), ConnectionState::Connected);
+        client
+            .run(&ArkCommand::Broadcast("boss fight\nin 5 min".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            mock.received(),
+            vec!["SaveWorld", "Broadcast boss fight in 5 min"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_connection_drops() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let client = RconClient::new(&mock.server_config(dir.path()));
+        client.run(&ArkCommand::ListPlayers).await.unwrap();
+
+        mock.drop_connections().await;
+        let response = client.run(&ArkCommand::SaveWorld).await.unwrap();
+        assert_eq!(response, ArkResponse::WorldSaved);
+        assert_eq!(mock.received(), vec!["ListPlayers", "SaveWorld"]);
+
+        // The server may have run a command it hung up after, so it is not
+        // sent again.
+        mock.reply_once("SaveWorld", Reply::Drop);
+        assert!(matches!(
+            client.run(&ArkCommand::SaveWorld).await,
+            Err(CommandError::Rcon(Error::Io(_)))
+        ));
+        assert_eq!(
+            mock.received(),
+            vec!["ListPlayers", "SaveWorld", "SaveWorld"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_empty_replies_and_auth_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let client = RconClient::new(&mock.server_config(dir.path()));
+
+        mock.reply_once("SaveWorld", Reply::Empty);
+        assert!(matches!(
+            client.run(&ArkCommand::SaveWorld).await,
+            Err(CommandError::Response(ResponseError::Empty))
+        ));
+
+        let rejected = RconClient::new(&mock.server_config(dir.path()));
+        mock.reject_auth(true);
+        assert!(matches!(
+            rejected.run(&ArkCommand::SaveWorld).await,
+            Err(CommandError::Rcon(Error::Auth))
+        ));
+        assert!(matches!(
+            rejected.state(),
+            ConnectionState::AuthFailed { .. }
+        ));
+    }
+}
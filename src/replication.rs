@@ -0,0 +1,438 @@
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::backup::{self, NAME_FORMAT};
+use crate::config::{BackupConfig, Config, ServerConfig, TargetConfig};
+use crate::error::{BotError, BotResult};
+use crate::labels;
+use crate::manifest::BackupInfo;
+use crate::retention::{self, BackupEntry};
+use crate::s3::S3Target;
+
+// A backup file held by a target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredFile {
+    pub name: String,
+    pub size: u64,
+}
+
+// Somewhere off the game host that backups are copied to. Files are grouped
+// by server and named like the backup files in `backup_dir`.
+#[async_trait]
+pub trait BackupTarget: Send + Sync {
+    async fn list(&self, server: &str) -> BotResult<Vec<StoredFile>>;
+
+    // Stores the file at `path` under its file name. An upload that was
+    // interrupted continues where it stopped rather than starting over.
+    async fn upload(&self, server: &str, path: &Path) -> BotResult<()>;
+
+    async fn delete(&self, server: &str, name: &str) -> BotResult<()>;
+}
+
+// A directory, such as one on a second disk or a network share.
+pub struct LocalTarget {
+    dir: PathBuf,
+}
+
+impl LocalTarget {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl BackupTarget for LocalTarget {
+    async fn list(&self, server: &str) -> BotResult<Vec<StoredFile>> {
+        let dir = self.dir.join(server);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if metadata.is_file() && !name.ends_with(backup::PARTIAL_SUFFIX) {
+                files.push(StoredFile {
+                    name,
+                    size: metadata.len(),
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    // Appends to what an earlier attempt left under the partial name, then
+    // gives the copy its final name once it is on disk.
+    async fn upload(&self, server: &str, path: &Path) -> BotResult<()> {
+        let dir = self.dir.join(server);
+        let source = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&dir)?;
+            let dest = dir.join(source.file_name().unwrap_or_default());
+            let partial = backup::partial_path(&dest);
+            let mut output = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&partial)?;
+            let mut input = File::open(&source)?;
+            let done = output.metadata()?.len();
+            if done > input.metadata()?.len() {
+                output.set_len(0)?;
+            } else if done > 0 {
+                input.seek(SeekFrom::Start(done))?;
+                info!(dest = %dest.display(), done, "resuming a copy");
+            }
+            std::io::copy(&mut input, &mut output)?;
+            output.sync_all()?;
+            std::fs::rename(&partial, &dest)?;
+            Ok(())
+        })
+        .await
+        .map_err(|e| BotError::Io(e.into()))?
+    }
+
+    async fn delete(&self, server: &str, name: &str) -> BotResult<()> {
+        std::fs::remove_file(self.dir.join(server).join(name))?;
+        Ok(())
+    }
+}
+
+struct Replica {
+    name: String,
+    target: Box<dyn BackupTarget>,
+    retention: BackupConfig,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+// What one pass did for one server and replica.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Replicated {
+    pub uploaded: Vec<String>,
+    pub pruned: Vec<String>,
+}
+
+// Copies every server's backups to the configured replicas. A pass runs at
+// startup, which also finishes uploads interrupted by a restart, and after
+// every backup; passes never overlap.
+pub struct Replicator {
+    config: Arc<Config>,
+    replicas: Vec<Replica>,
+    wake: Notify,
+    running: AsyncMutex<()>,
+}
+
+impl Replicator {
+    pub fn new(config: Arc<Config>) -> Self {
+        let replicas = config
+            .replicas
+            .iter()
+            .map(|replica| Replica {
+                name: replica.name.clone(),
+                target: match &replica.target {
+                    TargetConfig::Local { path } => Box::new(LocalTarget::new(path)),
+                    TargetConfig::S3(s3) => Box::new(S3Target::new(s3)),
+                },
+                retention: replica.retention.clone(),
+                retries: replica.retries,
+                retry_delay: Duration::from_secs(replica.retry_delay_secs),
+            })
+            .collect();
+        Self {
+            config,
+            replicas,
+            wake: Notify::new(),
+            running: AsyncMutex::new(()),
+        }
+    }
+
+    // Called after every backup; wakes `run` for another pass.
+    pub fn backed_up(&self) {
+        self.wake.notify_one();
+    }
+
+    // Runs passes until the bot exits; spawned once by `main`.
+    pub async fn run(&self) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        loop {
+            self.replicate_all().await;
+            self.wake.notified().await;
+        }
+    }
+
+    async fn replicate_all(&self) {
+        let _running = self.running.lock().await;
+        for replica in &self.replicas {
+            for (name, server) in &self.config.servers {
+                let span = info_span!("replicate", replica = %replica.name, server = %name);
+                match self
+                    .replicate(replica, name, server)
+                    .instrument(span.clone())
+                    .await
+                {
+                    Ok(done) => span.in_scope(|| {
+                        info!(
+                            uploaded = done.uploaded.len(),
+                            pruned = done.pruned.len(),
+                            "replicated"
+                        )
+                    }),
+                    Err(why) => span
+                        .in_scope(|| error!(kind = why.kind(), error = %why, "replication failed")),
+                }
+            }
+        }
+    }
+
+    // Applies the replica's retention policy to the backups it holds plus the
+    // zip backups on disk, then uploads what it keeps but lacks and deletes
+    // what it holds but does not keep. Pins apply to replicas as well.
+    async fn replicate(
+        &self,
+        replica: &Replica,
+        name: &str,
+        server: &ServerConfig,
+    ) -> BotResult<Replicated> {
+        let stored = with_retries(replica, "list", || replica.target.list(name)).await?;
+        let local: Vec<BackupEntry> = retention::scan(&server.backup_dir)?
+            .into_iter()
+            .filter(|e| !e.snapshot)
+            .collect();
+        let labels = labels::load(&server.backup_dir)?;
+
+        let mut entries = local.clone();
+        for file in &stored {
+            let stem = match file.name.strip_suffix(".zip") {
+                Some(stem) => stem,
+                None => continue,
+            };
+            let time = match NaiveDateTime::parse_from_str(stem, NAME_FORMAT) {
+                Ok(time) => time,
+                Err(_) => continue,
+            };
+            if local.iter().any(|e| e.name == stem) {
+                continue;
+            }
+            let label = labels.get(stem).cloned().unwrap_or_default();
+            entries.push(BackupEntry {
+                name: stem.to_string(),
+                path: PathBuf::from(&file.name),
+                time,
+                size: file.size,
+                snapshot: false,
+                info: BackupInfo::default(),
+                label: label.label,
+                pinned: label.pinned,
+            });
+        }
+        entries.sort_by_key(|e| std::cmp::Reverse(e.time));
+        let plan = retention::plan(&replica.retention, entries);
+
+        let mut done = Replicated::default();
+        for entry in &plan.keep {
+            let file_name = format!("{}.zip", entry.name);
+            let is_local = local.iter().any(|e| e.name == entry.name);
+            let is_stored = stored
+                .iter()
+                .any(|f| f.name == file_name && f.size == entry.size);
+            if !is_local || is_stored {
+                continue;
+            }
+            with_retries(replica, "upload", || {
+                replica.target.upload(name, &entry.path)
+            })
+            .await?;
+            info!(backup = %entry.name, "uploaded");
+            done.uploaded.push(entry.name.clone());
+        }
+        for entry in &plan.prune {
+            let file_name = format!("{}.zip", entry.name);
+            if !stored.iter().any(|f| f.name == file_name) {
+                continue;
+            }
+            with_retries(replica, "delete", || {
+                replica.target.delete(name, &file_name)
+            })
+            .await?;
+            info!(backup = %entry.name, "pruned from the replica");
+            done.pruned.push(entry.name.clone());
+        }
+        Ok(done)
+    }
+}
+
+// Runs `operation` until it succeeds or has failed `retries` more times,
+// doubling the wait after each failure.
+async fn with_retries<T, F, Fut>(replica: &Replica, what: &str, mut operation: F) -> BotResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = BotResult<T>>,
+{
+    let mut delay = replica.retry_delay;
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(why) if attempt < replica.retries => {
+                attempt += 1;
+                warn!(what, attempt, error = %why, "retrying after a failure");
+                sleep(delay).await;
+                delay *= 2;
+            }
+            Err(why) => return Err(why),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ReplicaConfig, S3Config};
+    use crate::s3::PART_SIZE;
+    use crate::test_support::fake_s3::FakeS3;
+    use crate::test_support::{config, server_config};
+
+    fn replica_config(target: TargetConfig, keep: usize) -> ReplicaConfig {
+        ReplicaConfig {
+            name: "offsite".to_string(),
+            target,
+            retention: BackupConfig {
+                keep,
+                ..Default::default()
+            },
+            retries: 1,
+            retry_delay_secs: 0,
+        }
+    }
+
+    // Writes zip-named backups `hours` hours apart, newest first, each `size`
+    // bytes long.
+    fn write_backups(dir: &Path, count: usize, size: usize) -> Vec<String> {
+        let newest = chrono::NaiveDate::from_ymd_opt(2023, 4, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        (0..count)
+            .map(|i| {
+                let time = newest - chrono::Duration::hours(i as i64);
+                let name = time.format(NAME_FORMAT).to_string();
+                std::fs::write(dir.join(format!("{}.zip", name)), vec![i as u8; size]).unwrap();
+                name
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replicas_follow_their_own_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        let remote = dir.path().join("remote");
+        let mut config = config(vec![("island", server.clone())]);
+        config.replicas = vec![replica_config(
+            TargetConfig::Local {
+                path: remote.clone(),
+            },
+            2,
+        )];
+        let replicator = Replicator::new(Arc::new(config));
+        let replica = &replicator.replicas[0];
+        let names = write_backups(&server.backup_dir, 3, 100);
+        labels::set_pinned(&server.backup_dir, &names[2], true).unwrap();
+
+        // An earlier copy of the newest backup was interrupted halfway.
+        std::fs::create_dir_all(remote.join("island")).unwrap();
+        let partial = remote
+            .join("island")
+            .join(format!("{}.zip.partial", names[0]));
+        std::fs::write(&partial, [0; 50]).unwrap();
+
+        let done = replicator
+            .replicate(replica, "island", &server)
+            .await
+            .unwrap();
+        assert_eq!(done.uploaded, names);
+        assert!(done.pruned.is_empty());
+        assert!(!partial.exists());
+        let copy = std::fs::read(remote.join("island").join(format!("{}.zip", names[0]))).unwrap();
+        assert_eq!(copy, vec![0; 100]);
+
+        // The replica keeps its two newest backups and the pinned one, even
+        // once they are gone from the game host.
+        std::fs::remove_file(server.backup_dir.join(format!("{}.zip", names[1]))).unwrap();
+        let newer = chrono::NaiveDate::from_ymd_opt(2023, 4, 1)
+            .unwrap()
+            .and_hms_opt(13, 0, 0)
+            .unwrap()
+            .format(NAME_FORMAT)
+            .to_string();
+        std::fs::write(server.backup_dir.join(format!("{}.zip", newer)), [9; 10]).unwrap();
+        let done = replicator
+            .replicate(replica, "island", &server)
+            .await
+            .unwrap();
+        assert_eq!(done.uploaded, vec![newer.clone()]);
+        assert_eq!(done.pruned, vec![names[1].clone()]);
+        let mut stored: Vec<_> = replica
+            .target
+            .list("island")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        stored.sort();
+        assert_eq!(
+            stored,
+            vec![
+                format!("{}.zip", names[2]),
+                format!("{}.zip", names[0]),
+                format!("{}.zip", newer),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_are_retried_and_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let s3 = FakeS3::start().await;
+        let server = server_config(dir.path());
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        let s3_config: S3Config = s3.config(dir.path());
+        let mut config = config(vec![("island", server.clone())]);
+        config.replicas = vec![replica_config(TargetConfig::S3(s3_config), 5)];
+        let replicator = Replicator::new(Arc::new(config));
+        let names = write_backups(&server.backup_dir, 1, PART_SIZE as usize + 1);
+
+        s3.fail_part(2);
+        let done = replicator
+            .replicate(&replicator.replicas[0], "island", &server)
+            .await
+            .unwrap();
+        assert_eq!(done.uploaded, names);
+        // The first part was not sent again on the retry.
+        assert_eq!(s3.parts_received(), 3);
+        assert_eq!(s3.keys(), vec![format!("island/{}.zip", names[0])]);
+
+        // Nothing is uploaded twice.
+        let done = replicator
+            .replicate(&replicator.replicas[0], "island", &server)
+            .await
+            .unwrap();
+        assert!(done.uploaded.is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use tracing::info;
+
+use crate::backup::NAME_FORMAT;
+use crate::catalog;
+use crate::config::BackupConfig;
+use crate::error::BotResult;
+use crate::labels;
+use crate::manifest::BackupInfo;
+use crate::snapshot::{self, Manifest};
+
+// A backup found in a server's backup directory: a zip archive or a snapshot
+// manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupEntry {
+    // The file name without its suffix, as taken by `/rollback`.
+    pub name: String,
+    pub path: PathBuf,
+    pub time: NaiveDateTime,
+    // For snapshots, the manifest plus the chunks the snapshot added.
+    pub size: u64,
+    pub snapshot: bool,
+    // From the catalog; empty until `catalog::index` has recorded the backup.
+    pub info: BackupInfo,
+    pub label: Option<String>,
+    // Pinned backups are kept whatever the policy says.
+    pub pinned: bool,
+}
+
+// Lists the backups in `dir`, newest first. Files whose names are not backup
+// timestamps are not ours and are never returned, so they are never pruned.
+pub fn scan(dir: &Path) -> BotResult<Vec<BackupEntry>> {
+    let mut labels = labels::load(dir)?;
+    let mut records = catalog::load(dir)?;
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let (stem, snapshot) = match split_file_name(name) {
+            Some(split) => split,
+            None => continue,
+        };
+        let time = match NaiveDateTime::parse_from_str(stem, NAME_FORMAT) {
+            Ok(time) => time,
+            Err(_) => continue,
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let mut size = metadata.len();
+        if snapshot {
+            size += Manifest::load(&path)?.added_bytes;
+        }
+        let label = labels.remove(stem).unwrap_or_default();
+        let info = records.remove(stem).map(|r| r.info).unwrap_or_default();
+        entries.push(BackupEntry {
+            name: stem.to_string(),
+            path,
+            time,
+            size,
+            snapshot,
+            info,
+            label: label.label,
+            pinned: label.pinned,
+        });
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.time));
+    Ok(entries)
+}
+
+// The backup name in a zip or manifest file name, and whether it is a snapshot.
+pub fn split_file_name(file_name: &str) -> Option<(&str, bool)> {
+    match file_name.strip_suffix(".zip") {
+        Some(stem) => Some((stem, false)),
+        None => Some((file_name.strip_suffix(snapshot::MANIFEST_SUFFIX)?, true)),
+    }
+}
+
+// Identifies the hour, day, week or month a backup was taken in.
+type Period = fn(&NaiveDateTime) -> (i32, u32, u32);
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RetentionPlan {
+    pub keep: Vec<BackupEntry>,
+    pub prune: Vec<BackupEntry>,
+}
+
+// Decides which of `entries` (newest first) the policy keeps. Pinned backups
+// are always kept and take up none of the policy's slots or size.
+pub fn plan(policy: &BackupConfig, entries: Vec<BackupEntry>) -> RetentionPlan {
+    let (pinned, entries): (Vec<_>, Vec<_>) = entries.into_iter().partition(|e| e.pinned);
+    let mut kept: HashSet<usize> = (0..policy.keep.min(entries.len())).collect();
+
+    // Grandfather-father-son: the newest backup in each of the most recent
+    // periods is kept.
+    let periods: [(usize, Period); 4] = [
+        (policy.keep_hourly, |t| (t.year(), t.ordinal(), t.hour())),
+        (policy.keep_daily, |t| (t.year(), t.ordinal(), 0)),
+        (policy.keep_weekly, |t| {
+            (t.iso_week().year(), t.iso_week().week(), 0)
+        }),
+        (policy.keep_monthly, |t| (t.year(), t.month(), 0)),
+    ];
+    for (count, period) in periods {
+        let mut seen = HashSet::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if seen.len() == count {
+                break;
+            }
+            if seen.insert(period(&entry.time)) {
+                kept.insert(i);
+            }
+        }
+    }
+
+    let mut result = RetentionPlan::default();
+    let mut total = 0;
+    let max_total = policy.max_total_size_mb.map(|mb| mb * 1024 * 1024);
+    for (i, entry) in entries.into_iter().enumerate() {
+        let fits = match max_total {
+            Some(max) => result.keep.is_empty() || total + entry.size <= max,
+            None => true,
+        };
+        if kept.contains(&i) && fits {
+            total += entry.size;
+            result.keep.push(entry);
+        } else {
+            result.prune.push(entry);
+        }
+    }
+    result.keep.extend(pinned);
+    result.keep.sort_by_key(|e| std::cmp::Reverse(e.time));
+    result
+}
+
+// Applies the policy to `dir`. With `dry_run` nothing is deleted, and the plan
+// only reports what would be.
+pub fn prune(policy: &BackupConfig, dir: &Path, dry_run: bool) -> BotResult<RetentionPlan> {
+    let plan = plan(policy, scan(dir)?);
+    if !dry_run {
+        remove(dir, &plan.prune)?;
+    }
+    Ok(plan)
+}
+
+// Deletes `entries` from `dir` along with their labels and catalog records.
+pub fn remove(dir: &Path, entries: &[BackupEntry]) -> BotResult<()> {
+    for entry in entries {
+        std::fs::remove_file(&entry.path)?;
+        info!(backup = %entry.name, "pruned backup");
+    }
+    labels::forget(dir, entries.iter().map(|e| e.name.as_str()))?;
+    catalog::forget(dir, entries.iter().map(|e| e.name.as_str()))?;
+    if entries.iter().any(|e| e.snapshot) {
+        snapshot::collect_garbage(dir, snapshot::GC_GRACE)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(keep: usize) -> BackupConfig {
+        BackupConfig {
+            keep,
+            ..Default::default()
+        }
+    }
+
+    // Backups every `step_hours`, newest first, starting at 2023-03-31 23:00.
+    fn backups(count: usize, step_hours: i64) -> Vec<BackupEntry> {
+        let newest = NaiveDateTime::parse_from_str("2023-03-31_(23-00-00)", NAME_FORMAT).unwrap();
+        (0..count)
+            .map(|i| {
+                let time = newest - chrono::Duration::hours(i as i64 * step_hours);
+                let name = time.format(NAME_FORMAT).to_string();
+                BackupEntry {
+                    path: PathBuf::from(format!("{}.zip", name)),
+                    name,
+                    time,
+                    size: 10,
+                    snapshot: false,
+                    info: BackupInfo::default(),
+                    label: None,
+                    pinned: false,
+                }
+            })
+            .collect()
+    }
+
+    fn names(entries: &[BackupEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_backups() {
+        let entries = backups(5, 1);
+        let plan = plan(&policy(2), entries.clone());
+        assert_eq!(plan.keep, entries[..2]);
+        assert_eq!(plan.prune, entries[2..]);
+    }
+
+    #[test]
+    fn keeps_one_backup_per_period() {
+        // Every 6 hours for 40 days.
+        let entries = backups(160, 6);
+        let policy = BackupConfig {
+            keep: 1,
+            keep_daily: 3,
+            keep_monthly: 2,
+            ..Default::default()
+        };
+        let plan = plan(&policy, entries);
+        assert_eq!(
+            names(&plan.keep),
+            vec![
+                "2023-03-31_(23-00-00)",
+                "2023-03-30_(23-00-00)",
+                "2023-03-29_(23-00-00)",
+                "2023-02-28_(23-00-00)",
+            ]
+        );
+        assert_eq!(plan.prune.len(), 156);
+    }
+
+    #[test]
+    fn weekly_and_hourly_buckets() {
+        let entries = backups(30, 12);
+        let policy = BackupConfig {
+            keep: 0,
+            keep_hourly: 2,
+            keep_weekly: 2,
+            ..Default::default()
+        };
+        // 2023-03-31 is a Friday, so the previous ISO week ends on the 26th.
+        assert_eq!(
+            names(&plan(&policy, entries).keep),
+            vec![
+                "2023-03-31_(23-00-00)",
+                "2023-03-31_(11-00-00)",
+                "2023-03-26_(23-00-00)",
+            ]
+        );
+    }
+
+    #[test]
+    fn prunes_down_to_the_size_limit_but_keeps_the_newest() {
+        let mut entries = backups(4, 1);
+        for entry in &mut entries {
+            entry.size = 400 * 1024;
+        }
+        let policy = BackupConfig {
+            keep: 4,
+            max_total_size_mb: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(plan(&policy, entries.clone()).keep, entries[..2]);
+
+        entries[0].size = 2 * 1024 * 1024;
+        assert_eq!(plan(&policy, entries.clone()).keep, entries[..1]);
+    }
+
+    #[test]
+    fn pinned_backups_are_kept_outside_the_policy() {
+        let mut entries = backups(5, 1);
+        entries[3].pinned = true;
+        let plan = plan(&policy(2), entries.clone());
+        assert_eq!(
+            names(&plan.keep),
+            vec![
+                "2023-03-31_(23-00-00)",
+                "2023-03-31_(22-00-00)",
+                "2023-03-31_(20-00-00)",
+            ]
+        );
+        assert_eq!(
+            names(&plan.prune),
+            vec!["2023-03-31_(21-00-00)", "2023-03-31_(19-00-00)"]
+        );
+    }
+
+    #[test]
+    fn dry_run_leaves_files_and_ignores_foreign_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        for entry in backups(3, 1) {
+            std::fs::write(dir.path().join(&entry.path), "zip").unwrap();
+        }
+        std::fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+        std::fs::write(dir.path().join("old.zip"), "mine").unwrap();
+
+        let plan = prune(&policy(1), dir.path(), true).unwrap();
+        assert_eq!(
+            names(&plan.prune),
+            vec!["2023-03-31_(22-00-00)", "2023-03-31_(21-00-00)"]
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 5);
+
+        prune(&policy(1), dir.path(), false).unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["2023-03-31_(23-00-00).zip", "notes.txt", "old.zip"]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::path::{Component, Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use tracing::debug;
+
+use crate::config::ServerConfig;
+
+// Files from `cluster_dir` are stored under this directory in backups.
+pub const CLUSTER_DIR: &str = "clusters";
+// Files from `config_files` are stored under this directory in backups, each
+// under its own name.
+pub const CONFIG_DIR: &str = "config";
+
+// Player and tribe data, and everything in the cluster directory, are backed
+// up along with the map save.
+const DEFAULT_INCLUDE: &[&str] = &[
+    "**/*.arkprofile",
+    "**/*.arktribe",
+    "**/*.arktributetribe",
+    "clusters/**",
+];
+
+// The timestamped copies ARK keeps of the map save, such as
+// `TheIsland_21.12.2022_16.11.21.ark`. They are only excluded when the map is
+// unknown; otherwise only `<map>.ark` is included in the first place.
+const ROLLING_SAVES: &str = "*_[0-9][0-9].[0-9][0-9].[0-9][0-9][0-9][0-9]_*.ark";
+
+// Decides which files under the save directories go into a backup. Patterns
+// match the names files have in the backup, relative to `savedata_path` with
+// `/` separators; `*` stays within one directory and `**` crosses them.
+pub struct SaveRules {
+    // The active map, such as `TheIsland` or `Fjordur`.
+    pub map: Option<String>,
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl SaveRules {
+    pub fn new(server: &ServerConfig) -> Result<Self, globset::Error> {
+        let map = server
+            .map
+            .clone()
+            .or_else(|| map_from_launch_args(&server.start_script));
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        match &map {
+            Some(map) => include.push(format!("{}.ark", globset::escape(map))),
+            None => {
+                include.push("*.ark".to_string());
+                exclude.push(ROLLING_SAVES.to_string());
+            }
+        }
+        include.extend(DEFAULT_INCLUDE.iter().map(|p| p.to_string()));
+        include.extend(server.include.iter().cloned());
+        exclude.extend(server.exclude.iter().cloned());
+        debug!(?map, ?include, ?exclude, "backup rules");
+        Ok(Self {
+            map,
+            include: glob_set(&include)?,
+            exclude: glob_set(&exclude)?,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.include.is_match(name) && !self.exclude.is_match(name)
+    }
+}
+
+pub fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    set.build()
+}
+
+// ARK takes the map as the first part of its URL-style launch argument, as in
+// `ShooterGameServer.exe TheIsland?listen?SessionName=...`, which the start
+// script passes along.
+fn map_from_launch_args(start_script: &Path) -> Option<String> {
+    let script = std::fs::read_to_string(start_script).ok()?;
+    let end = script.find("?listen")?;
+    let start = script[..end]
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(0, |i| i + 1);
+    let map = &script[start..end];
+    (!map.is_empty()).then(|| map.to_string())
+}
+
+// The name `path` gets in a backup: relative to `base`, with `/` separators.
+// `None` if it is not valid UTF-8.
+pub fn backup_name(base: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(base).ok()?;
+    let parts: Option<Vec<&str>> = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    Some(parts?.join("/"))
+}
+
+// The server settings backed up in the config section.
+pub fn config_paths(server: &ServerConfig) -> Vec<PathBuf> {
+    if let Some(paths) = &server.config_files {
+        return paths.clone();
+    }
+    // `savedata_path` is normally `ShooterGame/Saved/SavedArks`.
+    let saved = match server.savedata_path.parent() {
+        Some(saved) => saved,
+        None => return Vec::new(),
+    };
+    vec![
+        saved.join("Config").join("WindowsServer"),
+        saved.join("AllowedCheaterSteamIDs.txt"),
+        saved
+            .join("..")
+            .join("Binaries")
+            .join("Win64")
+            .join("BanList.txt"),
+    ]
+}
+
+// Where the backup entry `name` is restored to. `None` if the name could point
+// outside the server's directories, or if it is a settings file and
+// `with_config` is false.
+pub fn restore_path(server: &ServerConfig, name: &Path, with_config: bool) -> Option<PathBuf> {
+    if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    if let Ok(rest) = name.strip_prefix(CONFIG_DIR) {
+        if !with_config {
+            return None;
+        }
+        let mut parts = rest.components();
+        let label = parts.next()?.as_os_str();
+        let path = config_paths(server)
+            .into_iter()
+            .find(|p| p.file_name() == Some(label))?;
+        let rest = parts.as_path();
+        return Some(if rest.as_os_str().is_empty() {
+            path
+        } else {
+            path.join(rest)
+        });
+    }
+    match (&server.cluster_dir, name.strip_prefix(CLUSTER_DIR)) {
+        (Some(cluster_dir), Ok(rest)) => Some(cluster_dir.join(rest)),
+        _ => Some(server.savedata_path.join(name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::server_config;
+
+    #[test]
+    fn picks_the_active_map_and_player_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server_config(dir.path());
+        server.start_script = dir.path().join("start.ps1");
+        std::fs::write(
+            &server.start_script,
+            "Start-Process ShooterGameServer.exe \"Fjordur?listen?SessionName=fuwa\" -server",
+        )
+        .unwrap();
+        server.exclude = vec!["**/9*.arkprofile".to_string()];
+
+        let rules = SaveRules::new(&server).unwrap();
+        assert_eq!(rules.map.as_deref(), Some("Fjordur"));
+        for name in [
+            "Fjordur.ark",
+            "123.arkprofile",
+            "Fjordur/123.arkprofile",
+            "456.arktribe",
+            "456.arktributetribe",
+            "clusters/fuwa/76561198000000000",
+        ] {
+            assert!(rules.matches(name), "{}", name);
+        }
+        for name in [
+            "Fjordur_21.12.2022_16.11.21.ark",
+            "TheIsland.ark",
+            "123.profilebak",
+            "Fjordur.tmp",
+            "987.arkprofile",
+        ] {
+            assert!(!rules.matches(name), "{}", name);
+        }
+
+        // Without a map every map save is kept, but not the rolling copies.
+        server.start_script = dir.path().join("missing.ps1");
+        let rules = SaveRules::new(&server).unwrap();
+        assert!(rules.map.is_none());
+        assert!(rules.matches("TheIsland.ark"));
+        assert!(!rules.matches("TheIsland_21.12.2022_16.11.21.ark"));
+        assert!(!rules.matches("Backups/TheIsland.ark"));
+    }
+}
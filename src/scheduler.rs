@@ -0,0 +1,270 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::error::{BotError, BotResult};
+use crate::service::Notice;
+
+// A recurring SaveWorld and backup of one server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u32,
+    pub server: String,
+    // A cron expression with a seconds field, in local time.
+    pub expression: String,
+    pub paused: bool,
+}
+
+// A job as shown by `/schedule list`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JobStatus {
+    pub job: Job,
+    // `None` while paused, or if the expression never fires again.
+    pub next_run: Option<DateTime<Local>>,
+}
+
+struct Entry {
+    job: Job,
+    schedule: Schedule,
+}
+
+// The jobs the bot runs. They start out as the `schedule` lists of the server
+// profiles; once `/schedule` changes them they are saved to the state file,
+// which is used instead from then on.
+pub struct Scheduler {
+    state_file: PathBuf,
+    jobs: Mutex<Vec<Entry>>,
+    // Wakes the loop in `ArkService::run_schedule` when the jobs change.
+    changed: Notify,
+}
+
+impl Scheduler {
+    pub fn from_config(config: &Config) -> Self {
+        let jobs = config
+            .servers
+            .iter()
+            .flat_map(|(name, server)| server.schedule.iter().map(move |e| (name, e)))
+            .zip(1..)
+            .filter_map(|((server, expression), id)| {
+                entry(Job {
+                    id,
+                    server: server.clone(),
+                    expression: expression.clone(),
+                    paused: false,
+                })
+                .ok()
+            })
+            .collect();
+        Self {
+            state_file: config.schedule.state_file.clone(),
+            jobs: Mutex::new(jobs),
+            changed: Notify::new(),
+        }
+    }
+
+    // Replaces the jobs with those in the state file, if there is one. Jobs for
+    // servers no longer in `config` are dropped.
+    pub fn restore(&self, config: &Config) -> BotResult<()> {
+        if !self.state_file.exists() {
+            return Ok(());
+        }
+        let raw = std::fs::read(&self.state_file)?;
+        let saved: Vec<Job> = serde_json::from_slice(&raw).map_err(std::io::Error::from)?;
+        let mut jobs = Vec::new();
+        for job in saved {
+            if !config.servers.contains_key(&job.server) {
+                warn!(id = job.id, server = %job.server, "dropping a job for an unknown server");
+                continue;
+            }
+            jobs.push(entry(job).map_err(|why| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, why.to_string())
+            })?);
+        }
+        info!(jobs = jobs.len(), file = %self.state_file.display(), "restored the schedule");
+        *self.jobs.lock().unwrap() = jobs;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    pub fn jobs(&self) -> Vec<JobStatus> {
+        let now = Local::now();
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| JobStatus {
+                job: entry.job.clone(),
+                next_run: if entry.job.paused {
+                    None
+                } else {
+                    entry.schedule.after(&now).next()
+                },
+            })
+            .collect()
+    }
+
+    pub fn add(&self, server: &str, expression: &str) -> BotResult<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.iter().map(|e| e.job.id).max().unwrap_or(0) + 1;
+        let new = entry(Job {
+            id,
+            server: server.to_string(),
+            expression: expression.to_string(),
+            paused: false,
+        })
+        .map_err(|why| BotError::Precondition(Notice::InvalidSchedule(why.to_string())))?;
+        let job = new.job.clone();
+        jobs.push(new);
+        self.save(&jobs)?;
+        info!(id, server, expression, "added a scheduled job");
+        Ok(job)
+    }
+
+    pub fn remove(&self, id: u32) -> BotResult<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let index = position(&jobs, id)?;
+        let removed = jobs.remove(index).job;
+        self.save(&jobs)?;
+        info!(id, "removed a scheduled job");
+        Ok(removed)
+    }
+
+    pub fn set_paused(&self, id: u32, paused: bool) -> BotResult<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let index = position(&jobs, id)?;
+        jobs[index].job.paused = paused;
+        self.save(&jobs)?;
+        info!(id, paused, "changed a scheduled job");
+        Ok(jobs[index].job.clone())
+    }
+
+    // When the next job is due after `after`, and the servers of every job due
+    // then.
+    pub fn next_due(&self, after: DateTime<Local>) -> Option<(DateTime<Local>, Vec<String>)> {
+        let jobs = self.jobs.lock().unwrap();
+        let upcoming: Vec<_> = jobs
+            .iter()
+            .filter(|e| !e.job.paused)
+            .filter_map(|e| Some((e.schedule.after(&after).next()?, &e.job.server)))
+            .collect();
+        let first = upcoming.iter().map(|(at, _)| *at).min()?;
+        let mut servers: Vec<String> = upcoming
+            .into_iter()
+            .filter(|(at, _)| *at == first)
+            .map(|(_, server)| server.clone())
+            .collect();
+        servers.sort();
+        servers.dedup();
+        Some((first, servers))
+    }
+
+    // Resolves once the jobs have changed since the last call.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    fn save(&self, jobs: &[Entry]) -> BotResult<()> {
+        let saved: Vec<&Job> = jobs.iter().map(|e| &e.job).collect();
+        let raw = serde_json::to_vec_pretty(&saved).map_err(std::io::Error::from)?;
+        let partial = self.state_file.with_extension("json.partial");
+        std::fs::write(&partial, raw)?;
+        std::fs::rename(&partial, &self.state_file)?;
+        self.changed.notify_one();
+        Ok(())
+    }
+}
+
+fn entry(job: Job) -> Result<Entry, cron::error::Error> {
+    let schedule = Schedule::from_str(&job.expression)?;
+    Ok(Entry { job, schedule })
+}
+
+fn position(jobs: &[Entry], id: u32) -> BotResult<usize> {
+    jobs.iter()
+        .position(|e| e.job.id == id)
+        .ok_or_else(|| BotError::NotFound(format!("スケジュール #{}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config, server_config};
+    use chrono::TimeZone;
+
+    fn scheduler(dir: &std::path::Path) -> (Config, Scheduler) {
+        let mut island = server_config(dir);
+        island.schedule = vec!["0 0 * * * *".to_string()];
+        let mut ragnarok = island.clone();
+        ragnarok.schedule = vec!["0 0 */2 * * *".to_string(), "0 30 * * * *".to_string()];
+        let mut config = config(vec![("island", island), ("ragnarok", ragnarok)]);
+        config.schedule.state_file = dir.join("schedule.json");
+        let scheduler = Scheduler::from_config(&config);
+        (config, scheduler)
+    }
+
+    #[test]
+    fn finds_the_jobs_due_next() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, scheduler) = scheduler(dir.path());
+        let at = |h, m| Local.with_ymd_and_hms(2023, 4, 1, h, m, 0).unwrap();
+
+        assert_eq!(
+            scheduler.next_due(at(1, 10)),
+            Some((at(1, 30), vec!["ragnarok".to_string()]))
+        );
+        assert_eq!(
+            scheduler.next_due(at(1, 30)),
+            Some((at(2, 0), vec!["island".to_string(), "ragnarok".to_string()]))
+        );
+
+        scheduler.set_paused(1, true).unwrap();
+        assert_eq!(
+            scheduler.next_due(at(1, 30)),
+            Some((at(2, 0), vec!["ragnarok".to_string()]))
+        );
+        assert!(matches!(
+            scheduler.set_paused(9, true),
+            Err(BotError::NotFound(_))
+        ));
+
+        // A server with two jobs due at once is backed up once, wherever its
+        // jobs are in the list.
+        scheduler.set_paused(1, false).unwrap();
+        scheduler.add("island", "0 0 */2 * * *").unwrap();
+        assert_eq!(
+            scheduler.next_due(at(1, 30)),
+            Some((at(2, 0), vec!["island".to_string(), "ragnarok".to_string()]))
+        );
+    }
+
+    #[test]
+    fn changes_are_saved_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, scheduler) = scheduler(dir.path());
+        assert!(matches!(
+            scheduler.add("island", "every hour"),
+            Err(BotError::Precondition(Notice::InvalidSchedule(_)))
+        ));
+        let added = scheduler.add("island", "0 15 3 * * *").unwrap();
+        assert_eq!(added.id, 4);
+        scheduler.remove(2).unwrap();
+        scheduler.set_paused(3, true).unwrap();
+
+        let restored = Scheduler::from_config(&config);
+        restored.restore(&config).unwrap();
+        let jobs: Vec<_> = restored.jobs().into_iter().map(|s| s.job).collect();
+        assert_eq!(
+            jobs.iter().map(|j| (j.id, j.paused)).collect::<Vec<_>>(),
+            vec![(1, false), (3, true), (4, false)]
+        );
+        assert!(restored.jobs()[1].next_run.is_none());
+        assert_eq!(jobs[2], added);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+
+use rcon::Error;
+
+use crate::ark_command::{ArkCommand, ArkResponse, Player};
+use crate::rcon_client::{CommandError, RconClient};
+
+// What the bot can tell about a server before a lifecycle command acts on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerStatus {
+    Offline,
+    // The bot launched the server recently and RCON is not answering yet.
+    Starting,
+    Online { players: Vec<Player> },
+    RconAuthFailed,
+    // RCON answered, but not in a way that tells us whether players are online,
+    // or it could not be reached for a reason other than the server refusing
+    // the connection (a timeout, or waiting out the reconnect backoff).
+    Unknown,
+}
+
+impl ServerStatus {
+    pub async fn probe(rcon: &RconClient) -> Self {
+        match rcon.run(&ArkCommand::ListPlayers).await {
+            Ok(ArkResponse::Players(players)) => ServerStatus::Online { players },
+            Ok(_) => ServerStatus::Unknown,
+            Err(CommandError::Rcon(Error::Auth)) => ServerStatus::RconAuthFailed,
+            Err(CommandError::Rcon(Error::Io(_))) if rcon.is_starting() => ServerStatus::Starting,
+            Err(CommandError::Rcon(Error::Io(e)))
+                if e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                ServerStatus::Offline
+            }
+            Err(_) if rcon.is_starting() => ServerStatus::Starting,
+            Err(_) => ServerStatus::Unknown,
+        }
+    }
+
+    // Like `probe`, but connects now even while the client is backing off, for
+    // commands that must not act on a stale idea of whether the server is up.
+    pub async fn probe_now(rcon: &RconClient) -> Self {
+        rcon.retry_now();
+        Self::probe(rcon).await
+    }
+
+    // True only when the server is known to be reachable and empty.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, ServerStatus::Online { players } if players.is_empty())
+    }
+
+    // How many players are connected, when that is known.
+    pub fn player_count(&self) -> Option<usize> {
+        match self {
+            ServerStatus::Online { players } => Some(players.len()),
+            _ => None,
+        }
+    }
+
+    // True when players might be connected, including when we cannot tell.
+    pub fn may_have_players(&self) -> bool {
+        match self {
+            ServerStatus::Offline | ServerStatus::Starting => false,
+            ServerStatus::Online { players } => !players.is_empty(),
+            ServerStatus::RconAuthFailed | ServerStatus::Unknown => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ark_command::SteamId;
+    use crate::test_support::mock_rcon::MockArkServer;
+
+    #[tokio::test]
+    async fn reports_online_players() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let rcon = RconClient::new(&mock.server_config(dir.path()));
+
+        assert!(ServerStatus::probe(&rcon).await.is_empty());
+
+        mock.players(&[("Akh", 76561198012345678)]);
+        let status = ServerStatus::probe(&rcon).await;
+        assert!(status.may_have_players());
+        assert_eq!(
+            status,
+            ServerStatus::Online {
+                players: vec![Player {
+                    index: 0,
+                    name: "Akh".to_string(),
+                    steam_id: SteamId(76561198012345678),
+                }]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn distinguishes_offline_starting_and_auth_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let server = mock.server_config(dir.path());
+
+        mock.reject_auth(true);
+        let rcon = RconClient::new(&server);
+        assert_eq!(
+            ServerStatus::probe(&rcon).await,
+            ServerStatus::RconAuthFailed
+        );
+
+        mock.stop().await;
+        let rcon = RconClient::new(&server);
+        assert_eq!(ServerStatus::probe(&rcon).await, ServerStatus::Offline);
+        // While backing off the client has not seen the server refuse again.
+        assert_eq!(ServerStatus::probe(&rcon).await, ServerStatus::Unknown);
+        assert_eq!(ServerStatus::probe_now(&rcon).await, ServerStatus::Offline);
+
+        let rcon = RconClient::new(&server);
+        rcon.mark_starting();
+        assert_eq!(ServerStatus::probe(&rcon).await, ServerStatus::Starting);
+    }
+}
@@ -0,0 +1,1289 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+use crate::ark_command::{ArkCommand, ArkResponse, Player};
+use crate::backup::{self, BenchmarkResult, Progress};
+use crate::catalog::{BackupPage, Query};
+use crate::config::{Config, ServerConfig};
+use crate::coordinator::BackupCoordinator;
+use crate::disk;
+use crate::error::{BotError, BotResult};
+use crate::labels;
+use crate::manifest::Trigger;
+use crate::rcon_client::{CommandError, ConnectionState, RconClient, RconClients};
+use crate::replication::Replicator;
+use crate::retention;
+use crate::scheduler::{Job, JobStatus, Scheduler};
+use crate::server_status::ServerStatus;
+use crate::snapshot;
+use crate::verify::{self, Verification};
+
+// Where the service layer reports progress while an operation runs. Discord is
+// one implementation; the tests use a recording fake.
+#[async_trait]
+pub trait Frontend: Send + Sync {
+    async fn notify(&self, notice: Notice) -> BotResult<()>;
+}
+
+// Everything the bot tells its users, independent of how it is delivered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notice {
+    ServerRequired(Vec<String>),
+    Status(ServerStatus),
+    ServerCheck {
+        status: ServerStatus,
+        connection: ConnectionState,
+    },
+    Saving,
+    BackupStarted,
+    BackupProgress(Progress),
+    SaveRetrying,
+    SaveFailed,
+    NoOutput,
+    RconOutput(ArkResponse),
+    ScriptOutput(String),
+    AlreadyRunning,
+    ShuttingDown,
+    ShutdownUnconfirmed,
+    NotAcceptingCommands,
+    // Players are (or may be) online, so `command` was not run without `force`.
+    PlayersOnline {
+        command: &'static str,
+    },
+    Broadcast(String),
+    ArgumentRequired,
+    Players(Vec<Player>),
+    PlayerListFailed,
+    Backups(BackupPage),
+    BackupListUsage,
+    Labelled {
+        name: String,
+        label: String,
+    },
+    InvalidLabel,
+    Pinned {
+        name: String,
+        pinned: bool,
+    },
+    VerifyStarted,
+    Verified(Vec<Verification>),
+    BenchmarkStarted,
+    Benchmark {
+        original: u64,
+        results: Vec<BenchmarkResult>,
+    },
+    Pruned {
+        pruned: Vec<String>,
+        kept: usize,
+        dry_run: bool,
+    },
+    // A backup would not fit on the backup volume, in bytes.
+    InsufficientSpace {
+        needed: u64,
+        available: u64,
+    },
+    LowDiskSpace {
+        server: String,
+        available: u64,
+        threshold: u64,
+    },
+    DiskSpaceRecovered {
+        server: String,
+        available: u64,
+    },
+    RollbackStarted,
+    RollbackFinished {
+        with_config: bool,
+    },
+    RollbackConfirm,
+    BackupNameRequired,
+    ServerRunning,
+    TunnelStopped,
+    TunnelRunning,
+    ConnectionReloaded,
+    Schedules(Vec<JobStatus>),
+    ScheduleAdded(Job),
+    ScheduleRemoved(Job),
+    SchedulePaused(Job),
+    InvalidSchedule(String),
+    ScheduleUsage,
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notice::ServerRequired(names) => write!(
+                f,
+                "対象のサーバー名を指定してください．利用可能なサーバー: {}",
+                names.join(", ")
+            ),
+            Notice::Status(status) => match status {
+                ServerStatus::Offline => write!(f, "ARKサーバーは動作停止中です．"),
+                ServerStatus::Starting => write!(f, "ARKサーバーは起動中です．"),
+                ServerStatus::Online { players } => write!(
+                    f,
+                    "ARKサーバーは動作中です．(オンライン: {}人)",
+                    players.len()
+                ),
+                ServerStatus::RconAuthFailed => write!(
+                    f,
+                    "RCONの認証に失敗しました．RCONパスワードを確認してください．"
+                ),
+                ServerStatus::Unknown => write!(f, "ARKサーバーの状態を確認できませんでした．"),
+            },
+            Notice::ServerCheck { status, connection } => {
+                write!(f, "{}\nRCON: ", Notice::Status(status.clone()))?;
+                match connection {
+                    ConnectionState::Idle => write!(f, "未接続"),
+                    ConnectionState::Connected => write!(f, "接続中"),
+                    ConnectionState::Disconnected { failures, retry_at } => write!(
+                        f,
+                        "切断 (連続失敗 {} 回，{} 秒後に再接続)",
+                        failures,
+                        retry_at.saturating_duration_since(Instant::now()).as_secs()
+                    ),
+                    ConnectionState::AuthFailed { .. } => {
+                        write!(f, "認証失敗 (RCONパスワードを確認してください)")
+                    }
+                }
+            }
+            Notice::Saving => write!(f, "ゲームをセーブします．"),
+            Notice::BackupStarted => write!(f, "セーブとバックアップを開始しました．"),
+            Notice::BackupProgress(progress) => write!(
+                f,
+                "バックアップ中… {} / {} MB ({}%)",
+                progress.done / (1024 * 1024),
+                progress.total / (1024 * 1024),
+                progress.percent()
+            ),
+            Notice::SaveRetrying => write!(f, "セーブに失敗しました．再試行します．"),
+            Notice::SaveFailed => write!(f, "セーブに失敗しました．"),
+            Notice::NoOutput => write!(f, "No output was returned."),
+            Notice::RconOutput(output) => write!(f, "{}", output),
+            Notice::ScriptOutput(output) => write!(f, "{}", output),
+            Notice::AlreadyRunning => write!(f, "ARKサーバーは既に動作中です．"),
+            Notice::ShuttingDown => write!(f, "シャットダウンを開始します．"),
+            Notice::ShutdownUnconfirmed => write!(f, "シャットダウンが確認できませんでした．*/check_server*などのコマンドを使用してサーバーが正常終了しているかを確認してください．サーバーの起動は*/start_server*で行えます．"),
+            Notice::NotAcceptingCommands => write!(
+                f,
+                "サーバーがコマンドを受け付けていません．シャットダウンを中断します．"
+            ),
+            Notice::PlayersOnline { command } => {
+                let action = if *command == "reload_connection" {
+                    "再起動"
+                } else {
+                    "シャットダウン"
+                };
+                write!(f, "ゲームにプレイヤーが残っていたため，{}を中止しました．\n強制{}をする場合は*/{} force*を実行してください．", action, action, command)
+            }
+            Notice::Broadcast(text) => write!(f, "[Broadcast]\n{}", text),
+            Notice::ArgumentRequired => write!(f, "An argument is required."),
+            Notice::Players(players) if players.is_empty() => {
+                write!(f, "オンラインのプレイヤーはいません．")
+            }
+            Notice::Players(players) => {
+                let names: Vec<&str> = players.iter().map(|p| p.name.as_str()).collect();
+                write!(f, "{}", names.join("\n"))
+            }
+            Notice::PlayerListFailed => write!(f, "Failed to get the player list."),
+            Notice::Backups(page) => {
+                if page.total == 0 {
+                    return write!(f, "条件に合うバックアップはありません．");
+                }
+                writeln!(
+                    f,
+                    "バックアップ {}件 (ページ {}/{})",
+                    page.total, page.page, page.pages
+                )?;
+                for entry in &page.entries {
+                    write!(f, "`{}`", entry.name)?;
+                    if let Some(trigger) = entry.info.trigger {
+                        write!(f, " {}", trigger.description())?;
+                    }
+                    if let Some(map) = &entry.info.map {
+                        write!(f, " {}", map)?;
+                    }
+                    if let Some(players) = entry.info.players {
+                        write!(f, " {}人", players)?;
+                    }
+                    write!(f, " {:.1} MB", mb(entry.size))?;
+                    if entry.pinned {
+                        write!(f, " (固定)")?;
+                    }
+                    if let Some(label) = &entry.label {
+                        write!(f, " 「{}」", label)?;
+                    }
+                    writeln!(f)?;
+                }
+                if page.page < page.pages {
+                    write!(f, "次のページは*/listbackups page:{}*で表示できます．", page.page + 1)?;
+                }
+                Ok(())
+            }
+            Notice::BackupListUsage => write!(
+                f,
+                "使い方: */listbackups [サーバー名] [sort:newest|oldest|size] [trigger:scheduled|manual|pre-shutdown|pre-rollback] [from:2023-04-01] [to:2023-04-30] [page:2]*"
+            ),
+            Notice::Labelled { name, label } => {
+                write!(f, "バックアップ`{}`に「{}」と名前を付けました．", name, label)
+            }
+            Notice::InvalidLabel => write!(
+                f,
+                "名前が正しくありません．*/save label:\"名前\"*のように{}文字以内で指定してください．",
+                MAX_LABEL_CHARS
+            ),
+            Notice::Pinned { name, pinned: true } => write!(
+                f,
+                "バックアップ`{}`を固定しました．固定したバックアップは自動で削除されません．",
+                name
+            ),
+            Notice::Pinned {
+                name,
+                pinned: false,
+            } => write!(f, "バックアップ`{}`の固定を解除しました．", name),
+            Notice::VerifyStarted => write!(
+                f,
+                "バックアップを読み込んで検証します．しばらくお待ちください．"
+            ),
+            Notice::Verified(results) => {
+                let corrupt = results.iter().filter(|r| !r.is_ok()).count();
+                if corrupt == 0 {
+                    writeln!(f, "{}件のバックアップに問題はありませんでした．", results.len())?;
+                } else {
+                    writeln!(
+                        f,
+                        "{}件中{}件のバックアップが破損しています．",
+                        results.len(),
+                        corrupt
+                    )?;
+                }
+                for result in results {
+                    if result.is_ok() {
+                        write!(f, "`{}`: OK ({}ファイル)", result.name, result.files)?;
+                        if !result.manifest {
+                            write!(f, " マニフェストがないためCRCのみ確認しました")?;
+                        }
+                        writeln!(f)?;
+                    } else {
+                        writeln!(f, "`{}`: 破損", result.name)?;
+                        for problem in &result.problems {
+                            writeln!(f, "  - {}", problem)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Notice::BenchmarkStarted => write!(
+                f,
+                "各圧縮方式でセーブデータを圧縮します．しばらくお待ちください．"
+            ),
+            Notice::Benchmark { original, results } => {
+                writeln!(f, "元のサイズ: {:.1} MB", mb(*original))?;
+                for result in results {
+                    let level = match result.level {
+                        Some(level) => format!(" (レベル {})", level),
+                        None => String::new(),
+                    };
+                    let ratio = (result.bytes * 100).checked_div(*original).unwrap_or(100);
+                    writeln!(
+                        f,
+                        "`{}`{}: {:.1} MB ({}%), {:.1} 秒",
+                        result.compression.name(),
+                        level,
+                        mb(result.bytes),
+                        ratio,
+                        result.elapsed.as_secs_f64()
+                    )?;
+                }
+                Ok(())
+            }
+            Notice::Pruned {
+                pruned,
+                kept,
+                dry_run,
+            } => {
+                if pruned.is_empty() {
+                    return write!(f, "削除対象のバックアップはありません．(保持: {}件)", kept);
+                }
+                if *dry_run {
+                    writeln!(f, "以下のバックアップが削除対象です．(保持: {}件)", kept)?;
+                } else {
+                    writeln!(f, "以下のバックアップを削除しました．(保持: {}件)", kept)?;
+                }
+                for name in pruned {
+                    writeln!(f, "`{}`", name)?;
+                }
+                if *dry_run {
+                    write!(f, "削除するには*/prune_backups force*を実行してください．")?;
+                }
+                Ok(())
+            }
+            Notice::InsufficientSpace { needed, available } => write!(
+                f,
+                "ディスクの空き容量が足りないため，バックアップを中止しました．(必要: 約{:.1} MB，空き: {:.1} MB)\n固定したバックアップの固定を解除するか，ディスクの空きを増やしてください．",
+                mb(*needed),
+                mb(*available)
+            ),
+            Notice::LowDiskSpace {
+                server,
+                available,
+                threshold,
+            } => write!(
+                f,
+                "`{}`のバックアップ先のディスクの空き容量が少なくなっています．(空き: {:.1} MB，しきい値: {:.1} MB)",
+                server,
+                mb(*available),
+                mb(*threshold)
+            ),
+            Notice::DiskSpaceRecovered { server, available } => write!(
+                f,
+                "`{}`のバックアップ先のディスクの空き容量が回復しました．(空き: {:.1} MB)",
+                server,
+                mb(*available)
+            ),
+            Notice::RollbackStarted => write!(f, "ロールバックを開始します．"),
+            Notice::RollbackFinished { with_config: false } => write!(f, "ロールバックを正常に終了しました．"),
+            Notice::RollbackFinished { with_config: true } => {
+                write!(f, "設定ファイルを含めてロールバックを正常に終了しました．")
+            }
+            Notice::RollbackConfirm => write!(f, "ロールバックを行うと現在のデータは失われます．確認のため*/rollback force ファイル名*を実行してください．設定ファイルも戻す場合は*/rollback force config ファイル名*を実行してください．"),
+            Notice::BackupNameRequired => write!(f, "セーブデータ名を指定してください．利用可能なセーブデータは*/listbackups*で確認できます．"),
+            Notice::ServerRunning => write!(
+                f,
+                "ARKサーバーが動作中です．ロールバックを行う前にサーバーを停止してください．"
+            ),
+            Notice::TunnelStopped => write!(f, "playit.ggが起動されていません．*/reload_connection*を実行してplayit.ggを起動してください．"),
+            Notice::TunnelRunning => write!(f, "playit.ggは実行中です．回線に問題がある場合は*/reload_connection*を実行してください．"),
+            Notice::ConnectionReloaded => write!(f, "Connection Reloaded"),
+            Notice::Schedules(jobs) => {
+                if jobs.is_empty() {
+                    return write!(f, "スケジュールは登録されていません．");
+                }
+                for status in jobs {
+                    let job = &status.job;
+                    write!(f, "#{} `{}` `{}`", job.id, job.server, job.expression)?;
+                    match status.next_run {
+                        _ if job.paused => writeln!(f, " (一時停止中)")?,
+                        Some(at) => writeln!(f, " 次回: {}", at.format("%Y/%m/%d %H:%M:%S"))?,
+                        None => writeln!(f, " 次回の実行はありません")?,
+                    }
+                }
+                Ok(())
+            }
+            Notice::ScheduleAdded(job) => write!(
+                f,
+                "スケジュール #{} を追加しました．(`{}` `{}`)",
+                job.id, job.server, job.expression
+            ),
+            Notice::ScheduleRemoved(job) => {
+                write!(f, "スケジュール #{} を削除しました．", job.id)
+            }
+            Notice::SchedulePaused(job) if job.paused => {
+                write!(f, "スケジュール #{} を一時停止しました．", job.id)
+            }
+            Notice::SchedulePaused(job) => {
+                write!(f, "スケジュール #{} を再開しました．", job.id)
+            }
+            Notice::InvalidSchedule(why) => write!(
+                f,
+                "cron式が正しくありません: {}\n秒を含む6項目で指定してください．(例: `0 0 */2 * * *`)",
+                why
+            ),
+            Notice::ScheduleUsage => write!(
+                f,
+                "使い方: */schedule list*, */schedule add [サーバー名] <cron式>*, */schedule remove <番号>*, */schedule pause <番号>*, */schedule resume <番号>*"
+            ),
+        }
+    }
+}
+
+// The longest label `/save` accepts, so `/listbackups` stays readable.
+const MAX_LABEL_CHARS: usize = 50;
+
+fn mb(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+// The server a command acts on.
+#[derive(Clone, Copy)]
+pub struct Target<'a> {
+    pub name: &'a str,
+    pub server: &'a ServerConfig,
+}
+
+// The bot's operations, independent of the chat frontend that triggers them.
+pub struct ArkService {
+    config: Arc<Config>,
+    rcon: RconClients,
+    backups: BackupCoordinator,
+    replication: Arc<Replicator>,
+    schedule: Scheduler,
+}
+
+impl ArkService {
+    pub fn new(config: Arc<Config>) -> Self {
+        let rcon = RconClients::new(&config);
+        let replication = Arc::new(Replicator::new(Arc::clone(&config)));
+        let backups = BackupCoordinator::new(Arc::clone(&config), Arc::clone(&replication));
+        let schedule = Scheduler::from_config(&config);
+        Self {
+            config,
+            rcon,
+            backups,
+            replication,
+            schedule,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn rcon(&self, target: Target<'_>) -> Arc<RconClient> {
+        self.rcon.get(target.name)
+    }
+
+    // Splits an optional leading server name off the command arguments. Without
+    // one, the server bound to the channel (or the only configured server) is used.
+    pub fn select_server<'a, 'b>(
+        &'a self,
+        channel_id: u64,
+        args: &'b str,
+    ) -> BotResult<(Target<'a>, &'b str)> {
+        let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+        if let Some((name, server)) = self.config.server(first) {
+            return Ok((Target { name, server }, rest.trim()));
+        }
+        match self.config.default_server(channel_id) {
+            Some((name, server)) => Ok((Target { name, server }, args)),
+            None => Err(BotError::Precondition(Notice::ServerRequired(
+                self.config
+                    .server_names()
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+            ))),
+        }
+    }
+
+    // Loads the jobs saved by `/schedule`, if any.
+    pub fn restore_schedule(&self) -> BotResult<()> {
+        self.schedule.restore(&self.config)
+    }
+
+    // Runs the scheduled jobs until the bot exits; spawned once by `main`.
+    pub async fn run_schedule(&self) {
+        let mut last = chrono::Local::now();
+        loop {
+            let due = self.schedule.next_due(last);
+            let wait = async {
+                match &due {
+                    Some((at, _)) => {
+                        let delay = (*at - chrono::Local::now()).to_std().unwrap_or_default();
+                        sleep(delay).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                () = wait => {}
+                () = self.schedule.changed() => continue,
+            }
+            if let Some((at, servers)) = due {
+                for name in servers {
+                    self.scheduled_backup(&name).await;
+                }
+                // Runs missed while these took place are skipped.
+                last = at.max(chrono::Local::now());
+            }
+        }
+    }
+
+    // Copies backups to the replicas until the bot exits; spawned once by
+    // `main`.
+    pub async fn run_replication(&self) {
+        self.replication.run().await
+    }
+
+    // Watches the free space of the backup volumes until the bot exits, telling
+    // `alerts` when one runs low and when it recovers; spawned once by `main`.
+    // Without `alerts` this is only logged.
+    pub async fn run_disk_watch(&self, alerts: Option<&dyn Frontend>) {
+        let mut low = HashSet::new();
+        loop {
+            self.check_disk_space(&mut low, alerts, disk::available)
+                .await;
+            sleep(Duration::from_secs(self.config.disk.check_interval_secs)).await;
+        }
+    }
+
+    // One round of `run_disk_watch`. `low` holds the servers already reported
+    // low, so each drop is reported once.
+    async fn check_disk_space(
+        &self,
+        low: &mut HashSet<String>,
+        alerts: Option<&dyn Frontend>,
+        available: impl Fn(&Path) -> std::io::Result<u64>,
+    ) {
+        let threshold = self.config.disk.low_space_mb * 1024 * 1024;
+        for (name, server) in &self.config.servers {
+            let span = info_span!("disk_watch", server = %name);
+            let notice = span.in_scope(|| {
+                let free = match available(&server.backup_dir) {
+                    Ok(free) => free,
+                    Err(why) => {
+                        warn!(error = %why, "could not check free space");
+                        return None;
+                    }
+                };
+                if free < threshold && low.insert(name.clone()) {
+                    warn!(free, threshold, "backup volume is low on space");
+                    Some(Notice::LowDiskSpace {
+                        server: name.clone(),
+                        available: free,
+                        threshold,
+                    })
+                } else if free >= threshold && low.remove(name) {
+                    info!(free, threshold, "backup volume has space again");
+                    Some(Notice::DiskSpaceRecovered {
+                        server: name.clone(),
+                        available: free,
+                    })
+                } else {
+                    None
+                }
+            });
+            if let (Some(notice), Some(alerts)) = (notice, alerts) {
+                if let Err(why) = alerts.notify(notice).await {
+                    span.in_scope(|| error!(error = %why, "could not post a disk space alert"));
+                }
+            }
+        }
+    }
+
+    // Saves the world and backs it up, unless the server is offline.
+    async fn scheduled_backup(&self, name: &str) {
+        let span = info_span!("scheduled_backup", server = %name);
+        let result = async {
+            let rcon = self.rcon.get(name);
+            let status = ServerStatus::probe(&rcon).await;
+            if matches!(status, ServerStatus::Offline | ServerStatus::Starting) {
+                info!(?status, "server is not running, skipping");
+                return Ok(());
+            }
+            rcon.run(&ArkCommand::SaveWorld).await?;
+            let players = status.player_count();
+            self.backups
+                .request(name, Trigger::Scheduled, players)
+                .finished()
+                .await?;
+            Ok::<_, BotError>(())
+        }
+        .instrument(span.clone())
+        .await;
+        if let Err(why) = result {
+            span.in_scope(|| error!(kind = why.kind(), error = %why, "scheduled backup failed"));
+        }
+    }
+
+    // `/schedule list`, `add [server] <cron>`, `remove <id>`, `pause <id>` and
+    // `resume <id>`.
+    pub fn schedule(&self, channel_id: u64, args: &str) -> BotResult<Notice> {
+        let (command, rest) = args.split_once(' ').unwrap_or((args, ""));
+        let rest = rest.trim();
+        let id = || {
+            rest.parse::<u32>()
+                .map_err(|_| BotError::Precondition(Notice::ScheduleUsage))
+        };
+        match command {
+            "" | "list" => Ok(Notice::Schedules(self.schedule.jobs())),
+            "add" => {
+                let (target, expression) = self.select_server(channel_id, rest)?;
+                if expression.is_empty() {
+                    return Err(BotError::Precondition(Notice::ScheduleUsage));
+                }
+                let job = self.schedule.add(target.name, expression)?;
+                Ok(Notice::ScheduleAdded(job))
+            }
+            "remove" => Ok(Notice::ScheduleRemoved(self.schedule.remove(id()?)?)),
+            "pause" => Ok(Notice::SchedulePaused(
+                self.schedule.set_paused(id()?, true)?,
+            )),
+            "resume" => Ok(Notice::SchedulePaused(
+                self.schedule.set_paused(id()?, false)?,
+            )),
+            _ => Err(BotError::Precondition(Notice::ScheduleUsage)),
+        }
+    }
+
+    // Deletes what interrupted backups left behind. Runs at startup, before any
+    // backup can be in progress.
+    pub fn remove_partial_backups(&self) {
+        for (name, server) in &self.config.servers {
+            let _entered = info_span!("cleanup", server = %name).entered();
+            if let Err(why) = backup::remove_partials(&server.backup_dir) {
+                warn!(error = %why, "could not remove partial backups");
+            }
+        }
+    }
+
+    // `args` is empty or `label:<name>`, with the name optionally quoted; a
+    // labelled backup can be found in `/listbackups` and pinned.
+    pub async fn save(
+        &self,
+        target: Target<'_>,
+        args: &str,
+        frontend: &dyn Frontend,
+    ) -> BotResult<Notice> {
+        let label = parse_label(args)?;
+        match self.rcon(target).run(&ArkCommand::SaveWorld).await {
+            Ok(output) => {
+                frontend.notify(Notice::BackupStarted).await?;
+                // A labelled backup is not shared with other `/save` requests,
+                // or their labels would overwrite each other.
+                let shared = label.is_none();
+                let path = self
+                    .backup(target, Trigger::Manual, shared, frontend)
+                    .await?;
+                if let Some(label) = label {
+                    let name = backup_name(&path)?;
+                    labels::set_label(&target.server.backup_dir, &name, &label)?;
+                    info!(backup = %name, %label, "labelled a backup");
+                    frontend.notify(Notice::Labelled { name, label }).await?;
+                }
+                Ok(Notice::RconOutput(output))
+            }
+            Err(CommandError::Response(_)) => Ok(Notice::NoOutput),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    pub async fn broadcast(&self, target: Target<'_>, text: &str) -> BotResult<Notice> {
+        if text.is_empty() {
+            return Err(BotError::Precondition(Notice::ArgumentRequired));
+        }
+        self.rcon(target)
+            .run(&ArkCommand::Broadcast(text.to_string()))
+            .await?;
+        Ok(Notice::Broadcast(text.to_string()))
+    }
+
+    pub async fn start_server(&self, target: Target<'_>) -> BotResult<Notice> {
+        let rcon = self.rcon(target);
+        match ServerStatus::probe_now(&rcon).await {
+            ServerStatus::Offline => {
+                let output = run_script(&target.server.start_script).await?;
+                rcon.mark_starting();
+                Ok(script_notice(output))
+            }
+            ServerStatus::Online { .. } => Err(BotError::Precondition(Notice::AlreadyRunning)),
+            status => Err(BotError::Precondition(Notice::Status(status))),
+        }
+    }
+
+    // Saves, backs up and stops the server. With `restart`, the start script
+    // is run once the server has confirmed the shutdown.
+    pub async fn shutdown(
+        &self,
+        target: Target<'_>,
+        force: bool,
+        restart: bool,
+        frontend: &dyn Frontend,
+    ) -> BotResult<Notice> {
+        let rcon = self.rcon(target);
+        let status = ServerStatus::probe(&rcon).await;
+        if matches!(
+            status,
+            ServerStatus::Offline | ServerStatus::Starting | ServerStatus::RconAuthFailed
+        ) {
+            return Err(BotError::Precondition(Notice::Status(status)));
+        }
+        if !status.is_empty() && !force {
+            let command = if restart {
+                "restart_server"
+            } else {
+                "shutdown_server"
+            };
+            return Err(BotError::Precondition(Notice::PlayersOnline { command }));
+        }
+
+        frontend.notify(Notice::Saving).await?;
+        if !self.save_with_retries(target, &rcon, frontend).await? {
+            return Ok(Notice::NotAcceptingCommands);
+        }
+
+        frontend.notify(Notice::ShuttingDown).await?;
+        match rcon.run(&ArkCommand::DoExit).await {
+            Ok(output) if restart => {
+                frontend.notify(Notice::RconOutput(output)).await?;
+                let output = run_script(&target.server.start_script).await?;
+                rcon.mark_starting();
+                Ok(script_notice(output))
+            }
+            Ok(output) => Ok(Notice::RconOutput(output)),
+            Err(CommandError::Response(_)) if restart => Ok(Notice::ShutdownUnconfirmed),
+            Err(CommandError::Response(_)) => Ok(Notice::NoOutput),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    // Creates a backup, or with `shared` waits for one already queued,
+    // reporting its progress in quarter steps.
+    async fn backup(
+        &self,
+        target: Target<'_>,
+        trigger: Trigger,
+        shared: bool,
+        frontend: &dyn Frontend,
+    ) -> BotResult<PathBuf> {
+        let players = ServerStatus::probe(&self.rcon(target)).await.player_count();
+        let ticket = if shared {
+            self.backups.request(target.name, trigger, players)
+        } else {
+            self.backups.request_own(target.name, trigger, players)
+        };
+        let updates = ticket.progress.clone();
+        report_progress(ticket.finished(), updates, frontend).await
+    }
+
+    async fn save_with_retries(
+        &self,
+        target: Target<'_>,
+        rcon: &RconClient,
+        frontend: &dyn Frontend,
+    ) -> BotResult<bool> {
+        for i in 0..3 {
+            match rcon.run(&ArkCommand::SaveWorld).await {
+                Ok(output) => {
+                    frontend.notify(Notice::BackupStarted).await?;
+                    self.backup(target, Trigger::PreShutdown, true, frontend)
+                        .await?;
+                    frontend.notify(Notice::RconOutput(output)).await?;
+                    return Ok(true);
+                }
+                Err(CommandError::Response(_)) => {
+                    if i != 2 {
+                        frontend.notify(Notice::SaveRetrying).await?;
+                        sleep(Duration::from_millis(1000)).await;
+                    } else {
+                        frontend.notify(Notice::SaveFailed).await?;
+                    }
+                }
+                Err(why) => return Err(why.into()),
+            }
+        }
+        Ok(false)
+    }
+
+    pub async fn check_server(&self, target: Target<'_>) -> Notice {
+        let rcon = self.rcon(target);
+        let status = ServerStatus::probe(&rcon).await;
+        Notice::ServerCheck {
+            status,
+            connection: rcon.state(),
+        }
+    }
+
+    pub async fn list_players(&self, target: Target<'_>) -> BotResult<Notice> {
+        match self.rcon(target).run(&ArkCommand::ListPlayers).await {
+            Ok(ArkResponse::Players(players)) => Ok(Notice::Players(players)),
+            Err(why @ CommandError::Rcon(_)) => Err(why.into()),
+            _ => Ok(Notice::PlayerListFailed),
+        }
+    }
+
+    // `args` filters, sorts and pages the list; see `catalog::Query`.
+    // Refreshing the catalog may decrypt manifests and waits on the backup
+    // worker's lock, so it runs off the runtime threads.
+    pub async fn list_backups(&self, target: Target<'_>, args: &str) -> BotResult<Notice> {
+        let query = Query::parse(args)?;
+        let server = target.server.clone();
+        let span = Span::current();
+        let entries = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            backup::list_backups(&server)
+        })
+        .await
+        .map_err(|e| BotError::Io(e.into()))??;
+        Ok(Notice::Backups(query.apply(entries)))
+    }
+
+    // Pins or unpins the backup `name`. Pinned backups are never pruned.
+    pub async fn pin_backup(
+        &self,
+        target: Target<'_>,
+        name: &str,
+        pinned: bool,
+    ) -> BotResult<Notice> {
+        if name.is_empty() {
+            return Err(BotError::Precondition(Notice::BackupNameRequired));
+        }
+        // A retention pass must not delete the backup while it is being pinned.
+        let _no_backups = self.backups.lock().await;
+        let backup_dir = &target.server.backup_dir;
+        if !retention::scan(backup_dir)?.iter().any(|e| e.name == name) {
+            return Err(BotError::NotFound(format!("バックアップ `{}`", name)));
+        }
+        labels::set_pinned(backup_dir, name, pinned)?;
+        info!(backup = name, pinned, "changed a backup pin");
+        Ok(Notice::Pinned {
+            name: name.to_string(),
+            pinned,
+        })
+    }
+
+    // Re-reads the backup named in `args`, or every backup with `all`, and
+    // reports any that no longer match their manifest.
+    pub async fn verify_backup(
+        &self,
+        target: Target<'_>,
+        args: &str,
+        frontend: &dyn Frontend,
+    ) -> BotResult<Notice> {
+        if args.is_empty() {
+            return Err(BotError::Precondition(Notice::BackupNameRequired));
+        }
+        frontend.notify(Notice::VerifyStarted).await?;
+        let results = verify::verify_backups(target.server, args).await?;
+        Ok(Notice::Verified(results))
+    }
+
+    // Compresses the current save data with each codec and reports the results.
+    pub async fn benchmark_backup(
+        &self,
+        target: Target<'_>,
+        frontend: &dyn Frontend,
+    ) -> BotResult<Notice> {
+        frontend.notify(Notice::BenchmarkStarted).await?;
+        let (original, results) = backup::benchmark(target.server).await?;
+        Ok(Notice::Benchmark { original, results })
+    }
+
+    // Applies the retention policy to the server's backups. A dry run only
+    // reports what would be deleted.
+    pub async fn prune_backups(&self, target: Target<'_>, dry_run: bool) -> BotResult<Notice> {
+        let _no_backups = self.backups.lock().await;
+        let plan = retention::prune(&self.config.backup, &target.server.backup_dir, dry_run)?;
+        Ok(Notice::Pruned {
+            pruned: plan.prune.into_iter().map(|e| e.name).collect(),
+            kept: plan.keep.len(),
+            dry_run,
+        })
+    }
+
+    // `args` is `force [config] <backup name>`; without `force` the user is
+    // asked to confirm. With `config` the settings files are restored too.
+    pub async fn rollback(
+        &self,
+        target: Target<'_>,
+        args: &str,
+        frontend: &dyn Frontend,
+    ) -> BotResult<Notice> {
+        // サーバーが起動中かをチェック
+        match ServerStatus::probe_now(&self.rcon(target)).await {
+            ServerStatus::Offline => {}
+            ServerStatus::Online { .. } | ServerStatus::Starting => {
+                return Err(BotError::Precondition(Notice::ServerRunning))
+            }
+            status => return Err(BotError::Precondition(Notice::Status(status))),
+        }
+        // バックアップファイルが指定されているかをチェック
+        if args.is_empty() {
+            return Err(BotError::Precondition(Notice::BackupNameRequired));
+        }
+        // forceオプションの有無をチェック
+        let name = match args.strip_prefix("force") {
+            Some(name) => name.trim(),
+            None => return Err(BotError::Precondition(Notice::RollbackConfirm)),
+        };
+        let (name, with_config) = match name.strip_prefix("config ") {
+            Some(name) => (name.trim(), true),
+            None => (name, false),
+        };
+        if name.is_empty() {
+            return Err(BotError::Precondition(Notice::BackupNameRequired));
+        }
+
+        if !retention::scan(&target.server.backup_dir)?
+            .iter()
+            .any(|e| e.name == name)
+        {
+            return Err(BotError::NotFound(format!("バックアップ `{}`", name)));
+        }
+
+        frontend.notify(Notice::RollbackStarted).await?;
+        // No other backup may run from the one below until the save data has
+        // been replaced, or it would record a mix of both.
+        let no_backups = self.backups.lock().await;
+        // The data about to be replaced is backed up first, so a rollback to
+        // the wrong backup can itself be undone. The server is offline, so no
+        // players are recorded.
+        let (progress, updates) = watch::channel(Progress::default());
+        let job = self.backups.backup_locked(
+            &no_backups,
+            target.name,
+            Trigger::PreRollback,
+            None,
+            progress,
+        );
+        report_progress(job, updates, frontend).await?;
+        // Authenticating and extracting the archive is blocking work; the lock
+        // stays held until it is done.
+        let server = target.server.clone();
+        let name = name.to_string();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            backup::restore_backup(&server, &name, with_config)
+        })
+        .await
+        .map_err(|e| BotError::Io(e.into()))??;
+        drop(no_backups);
+        Ok(Notice::RollbackFinished { with_config })
+    }
+
+    pub async fn check_connection(&self) -> BotResult<Notice> {
+        let output = run_script(&self.config.tunnel.check_script).await?;
+        if output == "0" {
+            Ok(Notice::TunnelStopped)
+        } else {
+            Ok(Notice::TunnelRunning)
+        }
+    }
+
+    pub async fn reload_connection(&self, force: bool) -> BotResult<Notice> {
+        // The tunnel is shared by every server, so all of them have to be empty.
+        let mut players_online = false;
+        for name in self.config.servers.keys() {
+            if ServerStatus::probe(&self.rcon.get(name))
+                .await
+                .may_have_players()
+            {
+                players_online = true;
+            }
+        }
+        if players_online && !force {
+            return Err(BotError::Precondition(Notice::PlayersOnline {
+                command: "reload_connection",
+            }));
+        }
+        run_script(&self.config.tunnel.restart_script).await?;
+        Ok(Notice::ConnectionReloaded)
+    }
+}
+
+// Waits for `job`, posting its progress to `frontend` every quarter.
+async fn report_progress(
+    job: impl Future<Output = BotResult<PathBuf>>,
+    mut updates: watch::Receiver<Progress>,
+    frontend: &dyn Frontend,
+) -> BotResult<PathBuf> {
+    tokio::pin!(job);
+    let mut next_report = 25;
+    loop {
+        tokio::select! {
+            result = &mut job => return result,
+            Ok(()) = updates.changed() => {
+                let progress = *updates.borrow_and_update();
+                if progress.percent() >= next_report && progress.done < progress.total {
+                    next_report = progress.percent() / 25 * 25 + 25;
+                    frontend.notify(Notice::BackupProgress(progress)).await?;
+                }
+            }
+        }
+    }
+}
+
+async fn run_script(path: &Path) -> BotResult<String> {
+    let raw_output = Command::new("powershell")
+        .arg(path)
+        .output()
+        .await
+        .map_err(|source| BotError::Process {
+            script: path.to_path_buf(),
+            source,
+        })?;
+    Ok(String::from_utf8_lossy(&raw_output.stdout).into_owned())
+}
+
+// The label in `/save` arguments such as `label:"pre-boss"`, if any.
+fn parse_label(args: &str) -> BotResult<Option<String>> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let label = args
+        .strip_prefix("label:")
+        .ok_or(BotError::Precondition(Notice::InvalidLabel))?
+        .trim()
+        .trim_matches(|c| matches!(c, '"' | '“' | '”'))
+        .trim();
+    let valid = !label.is_empty()
+        && label.chars().count() <= MAX_LABEL_CHARS
+        && !label.contains(|c: char| c.is_control() || c == '`');
+    if !valid {
+        return Err(BotError::Precondition(Notice::InvalidLabel));
+    }
+    Ok(Some(label.to_string()))
+}
+
+// The name `/rollback` takes for the backup written to `path`.
+fn backup_name(path: &Path) -> BotResult<String> {
+    path.file_name()
+        .and_then(|name| retention::split_file_name(name.to_str()?))
+        .map(|(name, _)| name.to_string())
+        .ok_or_else(|| snapshot::invalid_data(format!("unexpected backup path {}", path.display())))
+}
+
+fn script_notice(output: String) -> Notice {
+    if output.is_empty() {
+        Notice::NoOutput
+    } else {
+        Notice::ScriptOutput(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::config;
+    use crate::test_support::fake_frontend::FakeFrontend;
+    use crate::test_support::mock_rcon::MockArkServer;
+
+    // A service for one mock server named `island`, with a save file to back up.
+    fn service(mock: &MockArkServer, dir: &Path) -> ArkService {
+        let mut server = mock.server_config(dir);
+        fs::create_dir_all(&server.savedata_path).unwrap();
+        fs::create_dir_all(&server.backup_dir).unwrap();
+        fs::write(server.savedata_path.join("TheIsland.ark"), "day 1").unwrap();
+        let settings = dir.join("Game.ini");
+        fs::write(&settings, "day 1").unwrap();
+        server.config_files = Some(vec![settings]);
+        ArkService::new(Arc::new(config(vec![("island", server)])))
+    }
+
+    fn target(service: &ArkService) -> Target<'_> {
+        service.select_server(0, "").unwrap().0
+    }
+
+    #[tokio::test]
+    async fn save_creates_a_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let service = service(&mock, dir.path());
+        let frontend = FakeFrontend::default();
+
+        let notice = service.save(target(&service), "", &frontend).await.unwrap();
+        assert_eq!(notice, Notice::RconOutput(ArkResponse::WorldSaved));
+        assert_eq!(frontend.notices(), vec![Notice::BackupStarted]);
+        match service.list_backups(target(&service), "").await.unwrap() {
+            Notice::Backups(page) => assert_eq!(page.total, 1),
+            other => panic!("unexpected notice: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn labelled_backups_can_be_pinned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let service = service(&mock, dir.path());
+        let frontend = FakeFrontend::default();
+
+        for args in ["pre-boss", "label:", "label:\"a`b\""] {
+            let result = service.save(target(&service), args, &frontend).await;
+            assert!(matches!(
+                result,
+                Err(BotError::Precondition(Notice::InvalidLabel))
+            ));
+        }
+        assert!(mock.received().is_empty());
+
+        service
+            .save(target(&service), "label:\"pre-boss\"", &frontend)
+            .await
+            .unwrap();
+        let entry = match service.list_backups(target(&service), "").await.unwrap() {
+            Notice::Backups(page) => page.entries[0].clone(),
+            other => panic!("unexpected notice: {:?}", other),
+        };
+        assert_eq!(entry.label.as_deref(), Some("pre-boss"));
+        assert!(!entry.pinned);
+        assert!(frontend.notices().contains(&Notice::Labelled {
+            name: entry.name.clone(),
+            label: "pre-boss".to_string(),
+        }));
+
+        let result = service
+            .pin_backup(target(&service), "2000-01-01_(00-00-00)", true)
+            .await;
+        assert!(matches!(result, Err(BotError::NotFound(_))));
+        service
+            .pin_backup(target(&service), &entry.name, true)
+            .await
+            .unwrap();
+
+        // With nothing else to keep, only the pin saves the backup.
+        let policy = crate::config::BackupConfig {
+            keep: 0,
+            ..Default::default()
+        };
+        let backup_dir = &target(&service).server.backup_dir;
+        let plan = retention::prune(&policy, backup_dir, false).unwrap();
+        assert!(plan.prune.is_empty());
+        let entries = retention::scan(backup_dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].pinned);
+        assert_eq!(entries[0].label.as_deref(), Some("pre-boss"));
+
+        service
+            .pin_backup(target(&service), &entry.name, false)
+            .await
+            .unwrap();
+        let plan = retention::prune(&policy, backup_dir, false).unwrap();
+        assert_eq!(plan.prune.len(), 1);
+        assert!(retention::scan(backup_dir).unwrap().is_empty());
+        assert!(labels::load(backup_dir).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_requires_force_while_players_are_online() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let service = service(&mock, dir.path());
+        let frontend = FakeFrontend::default();
+        mock.players(&[("Akh", 76561198012345678)]);
+
+        let result = service
+            .shutdown(target(&service), false, false, &frontend)
+            .await;
+        assert!(matches!(
+            result,
+            Err(BotError::Precondition(Notice::PlayersOnline {
+                command: "shutdown_server"
+            }))
+        ));
+        assert!(!mock.received().iter().any(|c| c == "DoExit"));
+
+        let notice = service
+            .shutdown(target(&service), true, false, &frontend)
+            .await
+            .unwrap();
+        assert_eq!(notice, Notice::RconOutput(ArkResponse::Exiting));
+        assert_eq!(
+            frontend.notices(),
+            vec![
+                Notice::Saving,
+                Notice::BackupStarted,
+                Notice::RconOutput(ArkResponse::WorldSaved),
+                Notice::ShuttingDown,
+            ]
+        );
+        assert_eq!(mock.received().last().unwrap(), "DoExit");
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_a_backup_once_the_server_is_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let service = service(&mock, dir.path());
+        let frontend = FakeFrontend::default();
+        service.save(target(&service), "", &frontend).await.unwrap();
+        let name = match service.list_backups(target(&service), "").await.unwrap() {
+            Notice::Backups(page) => page.entries[0].name.clone(),
+            other => panic!("unexpected notice: {:?}", other),
+        };
+        let save_file = dir.path().join("SavedArks").join("TheIsland.ark");
+        let settings = dir.path().join("Game.ini");
+        fs::write(&save_file, "day 2").unwrap();
+        fs::write(&settings, "day 2").unwrap();
+
+        let args = format!("force {}", name);
+        let result = service.rollback(target(&service), &args, &frontend).await;
+        assert!(matches!(
+            result,
+            Err(BotError::Precondition(Notice::ServerRunning))
+        ));
+
+        mock.stop().await;
+        let result = service.rollback(target(&service), &name, &frontend).await;
+        assert!(matches!(
+            result,
+            Err(BotError::Precondition(Notice::RollbackConfirm))
+        ));
+        for missing in [
+            "force 2000-01-01_(00-00-00)",
+            "force ../SavedArks/TheIsland",
+        ] {
+            let result = service.rollback(target(&service), missing, &frontend).await;
+            assert!(matches!(result, Err(BotError::NotFound(_))));
+        }
+        assert_eq!(fs::read_to_string(&save_file).unwrap(), "day 2");
+
+        let notice = service.rollback(target(&service), &args, &frontend).await;
+        assert_eq!(
+            notice.unwrap(),
+            Notice::RollbackFinished { with_config: false }
+        );
+        assert_eq!(fs::read_to_string(&save_file).unwrap(), "day 1");
+        assert_eq!(fs::read_to_string(&settings).unwrap(), "day 2");
+        // What the rollback replaced was backed up first.
+        match service
+            .list_backups(target(&service), "trigger:pre-rollback")
+            .await
+            .unwrap()
+        {
+            Notice::Backups(page) => assert_eq!(page.total, 1),
+            other => panic!("unexpected notice: {:?}", other),
+        }
+
+        let args = format!("force config {}", name);
+        let notice = service.rollback(target(&service), &args, &frontend).await;
+        assert_eq!(
+            notice.unwrap(),
+            Notice::RollbackFinished { with_config: true }
+        );
+        assert_eq!(fs::read_to_string(&settings).unwrap(), "day 1");
+    }
+
+    #[tokio::test]
+    async fn low_disk_space_is_reported_once_until_it_recovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = MockArkServer::start().await;
+        let service = service(&mock, dir.path());
+        let frontend = FakeFrontend::default();
+        let threshold = service.config.disk.low_space_mb * 1024 * 1024;
+        let free = std::cell::Cell::new(threshold * 2);
+        let mut low = HashSet::new();
+
+        for available in [threshold * 2, threshold - 1, 1, threshold, threshold + 1] {
+            free.set(available);
+            service
+                .check_disk_space(&mut low, Some(&frontend), |_| Ok(free.get()))
+                .await;
+        }
+        assert_eq!(
+            frontend.notices(),
+            vec![
+                Notice::LowDiskSpace {
+                    server: "island".to_string(),
+                    available: threshold - 1,
+                    threshold,
+                },
+                Notice::DiskSpaceRecovered {
+                    server: "island".to_string(),
+                    available: threshold,
+                },
+            ]
+        );
+    }
+}
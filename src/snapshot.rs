@@ -0,0 +1,433 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+use crate::backup::{self, Progress, SaveFile};
+use crate::error::BotResult;
+use crate::manifest::BackupInfo;
+
+// Deduplicated backups: save files are split into chunks stored once under
+// `<backup_dir>/objects/<first two hex digits>/<sha-256>`, and each backup is
+// a small manifest `<name>.snapshot.json` listing the chunks of every file.
+pub const MANIFEST_SUFFIX: &str = ".snapshot.json";
+const OBJECTS_DIR: &str = "objects";
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const MANIFEST_VERSION: u32 = 1;
+
+// Unreferenced chunks younger than this are left alone by garbage collection,
+// since a snapshot that is still being written may be about to use them.
+pub const GC_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u32,
+    pub entries: Vec<ManifestEntry>,
+    // Compressed size of the chunks this snapshot added to the store, which is
+    // what it costs on disk on top of the snapshots before it.
+    pub added_bytes: u64,
+    #[serde(flatten)]
+    pub info: BackupInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub dir: bool,
+    pub size: u64,
+    pub chunks: Vec<String>,
+    // SHA-256 of the whole file, so verification can check the chunks add up
+    // to what was read. Missing from snapshots taken before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> BotResult<Self> {
+        let raw = fs::read(path)?;
+        serde_json::from_slice(&raw).map_err(|e| std::io::Error::from(e).into())
+    }
+}
+
+pub fn manifest_path(backup_dir: &Path, name: &str) -> PathBuf {
+    backup_dir.join(format!("{}{}", name, MANIFEST_SUFFIX))
+}
+
+fn object_path(backup_dir: &Path, hash: &str) -> PathBuf {
+    backup_dir.join(OBJECTS_DIR).join(&hash[..2]).join(hash)
+}
+
+// Writes the snapshot `name` of `files`, storing only chunks the store does
+// not have yet. The manifest is written last and only takes its final name
+// once the snapshot verifies, so an interrupted or broken snapshot leaves at
+// most some unreferenced chunks and a partial manifest behind.
+pub fn write_snapshot(
+    backup_dir: &Path,
+    name: &str,
+    files: &[SaveFile],
+    info: &BackupInfo,
+    progress: &watch::Sender<Progress>,
+) -> BotResult<PathBuf> {
+    let total = files.iter().map(|f| f.size).sum();
+    let mut done = 0;
+    progress.send_replace(Progress { done, total });
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        entries: Vec::new(),
+        added_bytes: 0,
+        info: info.clone(),
+    };
+    let mut chunk = Vec::new();
+    for file in files {
+        let path = match &file.path {
+            Some(path) => path,
+            None => {
+                manifest.entries.push(ManifestEntry {
+                    path: file.name.clone(),
+                    dir: true,
+                    size: 0,
+                    chunks: Vec::new(),
+                    sha256: None,
+                });
+                continue;
+            }
+        };
+        debug!(file = %file.name, "adding to snapshot");
+        let mut f = File::open(path)?;
+        let mut entry = ManifestEntry {
+            path: file.name.clone(),
+            dir: false,
+            size: 0,
+            chunks: Vec::new(),
+            sha256: None,
+        };
+        let mut hasher = Sha256::new();
+        loop {
+            chunk.clear();
+            let n = (&mut f).take(CHUNK_SIZE).read_to_end(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&chunk);
+            let hash = hex::encode(Sha256::digest(&chunk));
+            let object = object_path(backup_dir, &hash);
+            if !object.exists() {
+                let compressed = zstd::encode_all(chunk.as_slice(), 0)?;
+                write_atomically(&object, &compressed)?;
+                manifest.added_bytes += compressed.len() as u64;
+            }
+            entry.size += n as u64;
+            entry.chunks.push(hash);
+            done += n as u64;
+            progress.send_replace(Progress { done, total });
+        }
+        entry.sha256 = Some(hex::encode(hasher.finalize()));
+        manifest.entries.push(entry);
+    }
+
+    let dest = manifest_path(backup_dir, name);
+    let partial = backup::partial_path(&dest);
+    let raw = serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::from)?;
+    write_synced(&partial, &raw)?;
+    backup::commit_partial(backup_dir, name, &partial, &dest, true, None)?;
+    info!(
+        dest = %dest.display(),
+        added_bytes = manifest.added_bytes,
+        "snapshot written"
+    );
+    Ok(dest)
+}
+
+// Recreates the files of the snapshot at `manifest` where `destination` puts
+// them, skipping those it returns `None` for. Every chunk is checked against
+// its hash before any file is touched, so a corrupt snapshot leaves the
+// destination as it was.
+pub fn restore_snapshot(
+    backup_dir: &Path,
+    manifest: &Path,
+    destination: impl Fn(&Path) -> Option<PathBuf>,
+) -> BotResult<()> {
+    let manifest = Manifest::load(manifest)?;
+    let mut restores = Vec::new();
+    for entry in &manifest.entries {
+        let relative = Path::new(&entry.path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(invalid_data(format!(
+                "unsafe path in manifest: {}",
+                entry.path
+            )));
+        }
+        let outpath = match destination(relative) {
+            Some(path) => path,
+            None => continue,
+        };
+        for hash in &entry.chunks {
+            read_chunk(backup_dir, hash)?;
+        }
+        restores.push((entry, outpath));
+    }
+    for (entry, outpath) in restores {
+        if entry.dir {
+            fs::create_dir_all(&outpath)?;
+            continue;
+        }
+        if let Some(parent) = outpath.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut outfile = File::create(&outpath)?;
+        for hash in &entry.chunks {
+            outfile.write_all(&read_chunk(backup_dir, hash)?)?;
+        }
+        debug!(path = %outpath.display(), bytes = entry.size, "restored file");
+    }
+    Ok(())
+}
+
+pub fn read_chunk(backup_dir: &Path, hash: &str) -> BotResult<Vec<u8>> {
+    let compressed = fs::read(object_path(backup_dir, hash))?;
+    let chunk = zstd::decode_all(compressed.as_slice())?;
+    if hex::encode(Sha256::digest(&chunk)) != hash {
+        return Err(invalid_data(format!("chunk {} is corrupt", hash)));
+    }
+    Ok(chunk)
+}
+
+// Deletes chunks no manifest in `backup_dir` refers to any more and that are
+// older than `grace`. Returns how many were deleted.
+pub fn collect_garbage(backup_dir: &Path, grace: Duration) -> BotResult<usize> {
+    let objects = backup_dir.join(OBJECTS_DIR);
+    if !objects.is_dir() {
+        return Ok(0);
+    }
+    let mut referenced = HashSet::new();
+    for entry in fs::read_dir(backup_dir)? {
+        let path = entry?.path();
+        let is_manifest = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with(MANIFEST_SUFFIX));
+        if is_manifest {
+            for entry in Manifest::load(&path)?.entries {
+                referenced.extend(entry.chunks);
+            }
+        }
+    }
+
+    let now = SystemTime::now();
+    let mut deleted = 0;
+    for prefix in fs::read_dir(&objects)? {
+        let prefix = prefix?.path();
+        if !prefix.is_dir() {
+            continue;
+        }
+        for object in fs::read_dir(&prefix)? {
+            let object = object?;
+            let hash = object.file_name().to_string_lossy().into_owned();
+            if referenced.contains(&hash) {
+                continue;
+            }
+            let age = now
+                .duration_since(object.metadata()?.modified()?)
+                .unwrap_or_default();
+            if age >= grace {
+                fs::remove_file(object.path())?;
+                deleted += 1;
+            }
+        }
+    }
+    if deleted > 0 {
+        info!(deleted, "deleted unreferenced chunks");
+    }
+    Ok(deleted)
+}
+
+// Writes through a partial file so a crash never leaves a truncated object
+// under its final name.
+fn write_atomically(path: &Path, data: &[u8]) -> BotResult<()> {
+    let partial = backup::partial_path(path);
+    write_synced(&partial, data)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> BotResult<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub fn invalid_data(message: String) -> crate::error::BotError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{create_backup, list_backups, restore_backup};
+    use crate::config::BackupFormat;
+    use crate::manifest::Trigger;
+    use crate::test_support::{config, server_config};
+
+    fn files(dir: &Path) -> Vec<SaveFile> {
+        let mut files = vec![SaveFile {
+            name: "SaveProfiles".to_string(),
+            path: None,
+            size: 0,
+        }];
+        for name in ["TheIsland.ark", "SaveProfiles/1.arkprofile"] {
+            let path = dir.join(name);
+            files.push(SaveFile {
+                name: name.to_string(),
+                size: fs::metadata(&path).unwrap().len(),
+                path: Some(path),
+            });
+        }
+        files
+    }
+
+    fn objects(backup_dir: &Path) -> usize {
+        walkdir::WalkDir::new(backup_dir.join(OBJECTS_DIR))
+            .into_iter()
+            .filter(|e| e.as_ref().unwrap().file_type().is_file())
+            .count()
+    }
+
+    #[test]
+    fn stores_unchanged_chunks_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let saves = dir.path().join("SavedArks");
+        let backups = dir.path().join("backups");
+        fs::create_dir_all(saves.join("SaveProfiles")).unwrap();
+        // Two chunks and a bit.
+        let map: Vec<u8> = (0..CHUNK_SIZE * 2 + 5).map(|i| (i % 253) as u8).collect();
+        fs::write(saves.join("TheIsland.ark"), &map).unwrap();
+        fs::write(saves.join("SaveProfiles/1.arkprofile"), "profile").unwrap();
+        let (progress, _) = watch::channel(Progress::default());
+
+        let first = write_snapshot(
+            &backups,
+            "first",
+            &files(&saves),
+            &BackupInfo::default(),
+            &progress,
+        )
+        .unwrap();
+        assert_eq!(objects(&backups), 4);
+        let second = write_snapshot(
+            &backups,
+            "second",
+            &files(&saves),
+            &BackupInfo::default(),
+            &progress,
+        )
+        .unwrap();
+        assert_eq!(Manifest::load(&second).unwrap().added_bytes, 0);
+        assert_eq!(objects(&backups), 4);
+
+        fs::write(saves.join("SaveProfiles/1.arkprofile"), "level 2").unwrap();
+        let third = write_snapshot(
+            &backups,
+            "third",
+            &files(&saves),
+            &BackupInfo::default(),
+            &progress,
+        )
+        .unwrap();
+        assert!(Manifest::load(&third).unwrap().added_bytes > 0);
+        assert_eq!(objects(&backups), 5);
+
+        let restored = dir.path().join("restored");
+        restore_snapshot(&backups, &first, |p| Some(restored.join(p))).unwrap();
+        assert_eq!(fs::read(restored.join("TheIsland.ark")).unwrap(), map);
+        assert_eq!(
+            fs::read_to_string(restored.join("SaveProfiles/1.arkprofile")).unwrap(),
+            "profile"
+        );
+
+        // Dropping the first two snapshots frees only the old profile chunk.
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+        assert_eq!(collect_garbage(&backups, GC_GRACE).unwrap(), 0);
+        assert_eq!(collect_garbage(&backups, Duration::ZERO).unwrap(), 1);
+        restore_snapshot(&backups, &third, |p| Some(restored.join(p))).unwrap();
+        assert_eq!(
+            fs::read_to_string(restored.join("SaveProfiles/1.arkprofile")).unwrap(),
+            "level 2"
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let saves = dir.path().join("SavedArks");
+        let backups = dir.path().join("backups");
+        fs::create_dir_all(saves.join("SaveProfiles")).unwrap();
+        fs::write(saves.join("TheIsland.ark"), "map").unwrap();
+        fs::write(saves.join("SaveProfiles/1.arkprofile"), "profile").unwrap();
+        let (progress, _) = watch::channel(Progress::default());
+        let manifest = write_snapshot(
+            &backups,
+            "only",
+            &files(&saves),
+            &BackupInfo::default(),
+            &progress,
+        )
+        .unwrap();
+
+        let hash = &Manifest::load(&manifest).unwrap().entries[1].chunks[0];
+        let tampered = zstd::encode_all("dodo".as_bytes(), 0).unwrap();
+        fs::write(object_path(&backups, hash), tampered).unwrap();
+        let restored = dir.path().join("restored");
+        fs::create_dir_all(restored.join("SaveProfiles")).unwrap();
+        fs::write(restored.join("TheIsland.ark"), "old map").unwrap();
+        fs::write(restored.join("SaveProfiles/1.arkprofile"), "old profile").unwrap();
+        assert!(restore_snapshot(&backups, &manifest, |p| Some(restored.join(p))).is_err());
+        // Nothing was overwritten before the corrupt chunk was found.
+        assert_eq!(
+            fs::read_to_string(restored.join("TheIsland.ark")).unwrap(),
+            "old map"
+        );
+        assert_eq!(
+            fs::read_to_string(restored.join("SaveProfiles/1.arkprofile")).unwrap(),
+            "old profile"
+        );
+    }
+
+    #[tokio::test]
+    async fn dedup_backups_can_be_listed_and_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server_config(dir.path());
+        server.format = BackupFormat::Dedup;
+        fs::create_dir_all(&server.savedata_path).unwrap();
+        fs::create_dir_all(&server.backup_dir).unwrap();
+        let save_file = server.savedata_path.join("TheIsland.ark");
+        fs::write(&save_file, "day 1").unwrap();
+        let config = config(vec![("island", server.clone())]);
+
+        let (progress, _) = watch::channel(Progress::default());
+        create_backup(&config, &server, Trigger::Manual, None, progress)
+            .await
+            .unwrap();
+        let entries = list_backups(&server).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].snapshot);
+
+        fs::write(&save_file, "day 2").unwrap();
+        restore_backup(&server, &entries[0].name, false).unwrap();
+        assert_eq!(fs::read_to_string(&save_file).unwrap(), "day 1");
+    }
+}
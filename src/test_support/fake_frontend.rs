@@ -0,0 +1,34 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::error::BotResult;
+use crate::service::{Frontend, Notice};
+
+// A frontend that records every notice instead of delivering it.
+#[derive(Default)]
+pub struct FakeFrontend {
+    notices: Mutex<Vec<Notice>>,
+}
+
+impl FakeFrontend {
+    // Every notice sent so far, in order, except progress reports: how many a
+    // backup gets depends on timing.
+    pub fn notices(&self) -> Vec<Notice> {
+        self.notices
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|n| !matches!(n, Notice::BackupProgress(_)))
+            .cloned()
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Frontend for FakeFrontend {
+    async fn notify(&self, notice: Notice) -> BotResult<()> {
+        self.notices.lock().unwrap().push(notice);
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::config::S3Config;
+
+const BUCKET: &str = "backups";
+const ACCESS_KEY: &str = "test-access-key";
+
+#[derive(Default)]
+struct State {
+    objects: BTreeMap<String, Vec<u8>>,
+    // Multipart uploads in progress: the key and the parts received so far.
+    uploads: HashMap<String, (String, BTreeMap<u32, Vec<u8>>)>,
+    next_upload: u32,
+    // The part number whose next upload is answered with an error.
+    fail_part: Option<u32>,
+    parts_received: usize,
+}
+
+// An in-process stand-in for MinIO that speaks just enough of the S3 API for
+// `S3Target`: single and multipart uploads, listing and deletion in one
+// bucket. Requests must carry a signature, which is not checked.
+pub struct FakeS3 {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeS3 {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = Arc::clone(&state);
+        let make_service = make_service_fn(move |_| {
+            let state = Arc::clone(&shared);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = Arc::clone(&state);
+                    async move { Ok::<_, Infallible>(handle(&state, request).await) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self { addr, state }
+    }
+
+    // A replica config for this server, with its credential files in `dir`.
+    pub fn config(&self, dir: &Path) -> S3Config {
+        let access_key_file = dir.join("s3_access_key");
+        let secret_key_file = dir.join("s3_secret_key");
+        std::fs::write(&access_key_file, format!("{}\n", ACCESS_KEY)).unwrap();
+        std::fs::write(&secret_key_file, "test-secret-key\n").unwrap();
+        S3Config {
+            endpoint: format!("http://{}", self.addr),
+            region: "us-east-1".to_string(),
+            bucket: BUCKET.to_string(),
+            prefix: String::new(),
+            access_key_file,
+            secret_key_file,
+        }
+    }
+
+    pub fn fail_part(&self, number: u32) {
+        self.state.lock().unwrap().fail_part = Some(number);
+    }
+
+    // How many part uploads were received, failed ones included.
+    pub fn parts_received(&self) -> usize {
+        self.state.lock().unwrap().parts_received
+    }
+
+    pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().objects.get(key).cloned()
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.state.lock().unwrap().objects.keys().cloned().collect()
+    }
+}
+
+async fn handle(state: &Mutex<State>, request: Request<Body>) -> Response<Body> {
+    let signed = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.starts_with(&format!("AWS4-HMAC-SHA256 Credential={}/", ACCESS_KEY))
+        });
+    if !signed {
+        return reply(
+            StatusCode::FORBIDDEN,
+            "<Error><Message>unsigned</Message></Error>",
+        );
+    }
+    let method = request.method().clone();
+    let path = decode(request.uri().path());
+    let query: HashMap<String, String> = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect();
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .unwrap()
+        .to_vec();
+    let key = match path.strip_prefix(&format!("/{}", BUCKET)) {
+        Some(rest) => rest.trim_start_matches('/').to_string(),
+        None => {
+            return reply(
+                StatusCode::NOT_FOUND,
+                "<Error><Message>no such bucket</Message></Error>",
+            )
+        }
+    };
+
+    let mut state = state.lock().unwrap();
+    let param = |name: &str| query.get(name).cloned().unwrap_or_default();
+    match (method, key.is_empty()) {
+        (Method::GET, true) if query.contains_key("uploads") => {
+            let uploads: String = state
+                .uploads
+                .iter()
+                .filter(|(_, (key, _))| key.starts_with(&param("prefix")))
+                .map(|(id, (key, _))| {
+                    format!(
+                        "<Upload><Key>{}</Key><UploadId>{}</UploadId></Upload>",
+                        key, id
+                    )
+                })
+                .collect();
+            reply(
+                StatusCode::OK,
+                &format!(
+                    "<ListMultipartUploadsResult>{}</ListMultipartUploadsResult>",
+                    uploads
+                ),
+            )
+        }
+        (Method::GET, true) => {
+            let contents: String = state
+                .objects
+                .iter()
+                .filter(|(key, _)| key.starts_with(&param("prefix")))
+                .map(|(key, data)| {
+                    format!(
+                        "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                        key,
+                        data.len()
+                    )
+                })
+                .collect();
+            reply(
+                StatusCode::OK,
+                &format!(
+                    "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                    contents
+                ),
+            )
+        }
+        (Method::POST, false) if query.contains_key("uploads") => {
+            state.next_upload += 1;
+            let id = format!("upload-{}", state.next_upload);
+            state.uploads.insert(id.clone(), (key, BTreeMap::new()));
+            reply(
+                StatusCode::OK,
+                &format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    id
+                ),
+            )
+        }
+        (Method::PUT, false) if query.contains_key("partNumber") => {
+            state.parts_received += 1;
+            let number: u32 = param("partNumber").parse().unwrap();
+            if state.fail_part == Some(number) {
+                state.fail_part = None;
+                return reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "<Error><Message>injected failure</Message></Error>",
+                );
+            }
+            let etag = etag(&body);
+            match state.uploads.get_mut(&param("uploadId")) {
+                Some((_, parts)) => {
+                    parts.insert(number, body);
+                    Response::builder()
+                        .header("ETag", etag)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                None => reply(
+                    StatusCode::NOT_FOUND,
+                    "<Error><Message>no such upload</Message></Error>",
+                ),
+            }
+        }
+        (Method::GET, false) if query.contains_key("uploadId") => {
+            let parts: String = match state.uploads.get(&param("uploadId")) {
+                Some((_, parts)) => parts
+                    .iter()
+                    .map(|(number, data)| {
+                        format!(
+                            "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag><Size>{}</Size></Part>",
+                            number,
+                            etag(data).replace('"', "&quot;"),
+                            data.len()
+                        )
+                    })
+                    .collect(),
+                None => {
+                    return reply(StatusCode::NOT_FOUND, "<Error><Message>no such upload</Message></Error>")
+                }
+            };
+            reply(
+                StatusCode::OK,
+                &format!(
+                    "<ListPartsResult><IsTruncated>false</IsTruncated>{}</ListPartsResult>",
+                    parts
+                ),
+            )
+        }
+        (Method::POST, false) if query.contains_key("uploadId") => {
+            let (upload_key, parts) = match state.uploads.remove(&param("uploadId")) {
+                Some(upload) => upload,
+                None => {
+                    return reply(
+                        StatusCode::NOT_FOUND,
+                        "<Error><Message>no such upload</Message></Error>",
+                    )
+                }
+            };
+            let requested = String::from_utf8(body).unwrap();
+            let mut object = Vec::new();
+            for (number, part) in parts {
+                let expected = format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    number,
+                    etag(&part).replace('"', "&quot;")
+                );
+                assert!(
+                    requested.contains(&expected),
+                    "{} not in {}",
+                    expected,
+                    requested
+                );
+                object.extend(part);
+            }
+            state.objects.insert(upload_key, object);
+            reply(
+                StatusCode::OK,
+                "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>",
+            )
+        }
+        (Method::PUT, false) => {
+            state.objects.insert(key, body);
+            reply(StatusCode::OK, "")
+        }
+        (Method::DELETE, false) => {
+            state.objects.remove(&key);
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()
+        }
+        _ => reply(
+            StatusCode::BAD_REQUEST,
+            "<Error><Message>unsupported</Message></Error>",
+        ),
+    }
+}
+
+fn reply(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", &hex::encode(Sha256::digest(data))[..32])
+}
+
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            decoded.push(u8::from_str_radix(&text[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::config::ServerConfig;
+
+const AUTH: i32 = 3;
+const AUTH_RESPONSE: i32 = 2;
+const RESPONSE_VALUE: i32 = 0;
+
+pub const PASSWORD: &str = "mock-password";
+
+// What the mock server does when it receives a command.
+#[derive(Clone, Debug)]
+pub enum Reply {
+    Text(String),
+    // An empty response packet, which ARK sends when it ignores a command.
+    Empty,
+    // Close the connection without answering.
+    Drop,
+}
+
+impl Reply {
+    pub fn text(text: &str) -> Self {
+        Reply::Text(text.to_string())
+    }
+}
+
+struct Script {
+    replies: HashMap<String, Reply>,
+    queued: HashMap<String, VecDeque<Reply>>,
+    reject_auth: bool,
+    received: Vec<String>,
+}
+
+impl Script {
+    fn reply_for(&mut self, command: &str) -> Reply {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        if let Some(reply) = self.queued.get_mut(name).and_then(VecDeque::pop_front) {
+            return reply;
+        }
+        self.replies
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Reply::text("Server received, But no response!! \n"))
+    }
+}
+
+// An in-process Source RCON server that answers like ARK does. Replies are
+// keyed by the command name (the first word of the command line).
+pub struct MockArkServer {
+    addr: SocketAddr,
+    script: Arc<Mutex<Script>>,
+    accept: Mutex<Option<JoinHandle<()>>>,
+    // One task per open connection.
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl MockArkServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind the mock RCON server");
+        let addr = listener.local_addr().unwrap();
+        let script = Arc::new(Mutex::new(Script {
+            replies: HashMap::from([
+                ("SaveWorld".to_string(), Reply::text("World Saved \n")),
+                ("DoExit".to_string(), Reply::text("Exiting... \n")),
+                (
+                    "ListPlayers".to_string(),
+                    Reply::text("No Players Connected \n"),
+                ),
+            ]),
+            queued: HashMap::new(),
+            reject_auth: false,
+            received: Vec::new(),
+        }));
+        let tasks = Arc::new(Mutex::new(Vec::new()));
+
+        let accept_script = Arc::clone(&script);
+        let accept_tasks = Arc::clone(&tasks);
+        let accept = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let conn = tokio::spawn(serve(stream, Arc::clone(&accept_script)));
+                accept_tasks.lock().unwrap().push(conn);
+            }
+        });
+
+        Self {
+            addr,
+            script,
+            accept: Mutex::new(Some(accept)),
+            tasks,
+        }
+    }
+
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+
+    // A server profile pointing at this mock, with its save and backup
+    // directories under `dir`.
+    pub fn server_config(&self, dir: &Path) -> ServerConfig {
+        std::fs::write(dir.join("rcon_password"), format!("{}\n", PASSWORD)).unwrap();
+        ServerConfig {
+            rcon_address: self.address(),
+            ..super::server_config(dir)
+        }
+    }
+
+    // Sets the reply for every later `command`.
+    pub fn reply(&self, command: &str, reply: Reply) {
+        self.script
+            .lock()
+            .unwrap()
+            .replies
+            .insert(command.to_string(), reply);
+    }
+
+    // Queues a reply used once before falling back to the regular one.
+    pub fn reply_once(&self, command: &str, reply: Reply) {
+        self.script
+            .lock()
+            .unwrap()
+            .queued
+            .entry(command.to_string())
+            .or_default()
+            .push_back(reply);
+    }
+
+    pub fn players(&self, players: &[(&str, u64)]) {
+        let text = if players.is_empty() {
+            "No Players Connected \n".to_string()
+        } else {
+            players
+                .iter()
+                .enumerate()
+                .map(|(i, (name, id))| format!("{}. {}, {}\n", i, name, id))
+                .collect()
+        };
+        self.reply("ListPlayers", Reply::Text(text));
+    }
+
+    pub fn reject_auth(&self, reject: bool) {
+        self.script.lock().unwrap().reject_auth = reject;
+    }
+
+    // Every command line received so far, in order.
+    pub fn received(&self) -> Vec<String> {
+        self.script.lock().unwrap().received.clone()
+    }
+
+    // Stops listening and drops every open connection, as if the server exited.
+    // Once it returns, new connections are refused.
+    pub async fn stop(&self) {
+        let accept = self.accept.lock().unwrap().take();
+        if let Some(accept) = accept {
+            accept.abort();
+            let _ = accept.await;
+        }
+        self.drop_connections().await;
+    }
+
+    // Closes every open connection but keeps listening, as if the server had
+    // restarted between two commands.
+    pub async fn drop_connections(&self) {
+        let tasks: Vec<_> = self.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for MockArkServer {
+    fn drop(&mut self) {
+        let accept = self.accept.lock().unwrap().take();
+        for task in accept
+            .into_iter()
+            .chain(self.tasks.lock().unwrap().drain(..))
+        {
+            task.abort();
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, script: Arc<Mutex<Script>>) {
+    let _ = serve_packets(&mut stream, &script).await;
+}
+
+async fn serve_packets(stream: &mut TcpStream, script: &Mutex<Script>) -> io::Result<()> {
+    loop {
+        let (id, ptype, body) = read_packet(stream).await?;
+        if ptype == AUTH {
+            let reject = script.lock().unwrap().reject_auth;
+            // ARK sends an empty response value before the auth result.
+            write_packet(stream, id, RESPONSE_VALUE, "").await?;
+            let auth_id = if reject || body != PASSWORD { -1 } else { id };
+            write_packet(stream, auth_id, AUTH_RESPONSE, "").await?;
+            continue;
+        }
+
+        let reply = {
+            let mut script = script.lock().unwrap();
+            script.received.push(body.clone());
+            script.reply_for(&body)
+        };
+        match reply {
+            Reply::Text(text) => write_packet(stream, id, RESPONSE_VALUE, &text).await?,
+            Reply::Empty => write_packet(stream, id, RESPONSE_VALUE, "").await?,
+            Reply::Drop => return Ok(()),
+        }
+    }
+}
+
+async fn read_packet(stream: &mut TcpStream) -> io::Result<(i32, i32, String)> {
+    let length = stream.read_i32_le().await?;
+    let id = stream.read_i32_le().await?;
+    let ptype = stream.read_i32_le().await?;
+    let mut body = vec![0; (length - 8).max(0) as usize];
+    stream.read_exact(&mut body).await?;
+    // Body and packet terminators.
+    body.truncate(body.len().saturating_sub(2));
+    let body =
+        String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((id, ptype, body))
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, ptype: i32, body: &str) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(body.len() + 14);
+    buf.extend_from_slice(&(body.len() as i32 + 10).to_le_bytes());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&ptype.to_le_bytes());
+    buf.extend_from_slice(body.as_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    stream.write_all(&buf).await
+}
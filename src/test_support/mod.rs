@@ -0,0 +1,54 @@
+// Helpers shared by the unit tests. Nothing in here is compiled into the bot.
+
+pub mod fake_frontend;
+pub mod fake_s3;
+pub mod mock_rcon;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{BackupFormat, Compression, Config, ServerConfig};
+
+// A config with default settings and the given server profiles.
+pub fn config(servers: Vec<(&str, ServerConfig)>) -> Config {
+    Config {
+        discord: Default::default(),
+        backup: Default::default(),
+        log: Default::default(),
+        schedule: Default::default(),
+        disk: Default::default(),
+        tunnel: Default::default(),
+        replicas: Vec::new(),
+        servers: servers
+            .into_iter()
+            .map(|(name, server)| (name.to_string(), server))
+            .collect::<BTreeMap<_, _>>(),
+    }
+}
+
+// A server profile with its save and backup directories under `dir`, for tests
+// that do not talk to the server. Nothing answers RCON at its address; see
+// `MockArkServer::server_config` for tests that need it to.
+pub fn server_config(dir: &Path) -> ServerConfig {
+    ServerConfig {
+        rcon_address: "127.0.0.1:0".to_string(),
+        rcon_password_file: dir.join("rcon_password"),
+        savedata_path: dir.join("SavedArks"),
+        backup_dir: dir.join("backups"),
+        start_script: PathBuf::from("scripts/start_ark_server.ps1"),
+        format: BackupFormat::default(),
+        compression: Compression::default(),
+        compression_level: None,
+        map: None,
+        cluster_dir: None,
+        include: Vec::new(),
+        exclude: Vec::new(),
+        config_files: Some(Vec::new()),
+        version_file: None,
+        encryption_key: None,
+        encryption_key_file: None,
+        allow_unencrypted_backups: false,
+        schedule: Vec::new(),
+        channels: Vec::new(),
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn, Span};
+
+use crate::config::ServerConfig;
+use crate::crypto::{self, Key};
+use crate::error::{BotError, BotResult};
+use crate::manifest::{self, ZipManifest};
+use crate::retention;
+use crate::snapshot::{self, Manifest};
+
+// The outcome of re-reading one backup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub name: String,
+    pub files: usize,
+    // False for zips written before manifests existed, which can only be
+    // checked against the CRCs inside the archive.
+    pub manifest: bool,
+    // Empty when the backup is intact.
+    pub problems: Vec<String>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Re-reads the backup `which`, or every backup with "all", and checks each
+// file against its manifest.
+pub async fn verify_backups(server: &ServerConfig, which: &str) -> BotResult<Vec<Verification>> {
+    let server = server.clone();
+    let which = which.to_string();
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let key = Key::for_server(&server)?;
+        let mut entries = retention::scan(&server.backup_dir)?;
+        if which != "all" {
+            entries.retain(|e| e.name == which);
+            if entries.is_empty() {
+                return Err(BotError::NotFound(format!("バックアップ `{}`", which)));
+            }
+        }
+        let results: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                verify(
+                    &server.backup_dir,
+                    &entry.name,
+                    &entry.path,
+                    entry.snapshot,
+                    key.as_ref(),
+                )
+            })
+            .collect();
+        for result in &results {
+            if result.is_ok() {
+                info!(backup = %result.name, files = result.files, "backup verified");
+            } else {
+                warn!(backup = %result.name, problems = ?result.problems, "backup is corrupt");
+            }
+        }
+        Ok(results)
+    })
+    .await
+    .map_err(|e| BotError::Io(e.into()))?
+}
+
+// Checks the zip archive or snapshot manifest at `path`, which need not have
+// its final name yet. Encrypted archives are decrypted with `key`.
+pub fn verify(
+    backup_dir: &Path,
+    name: &str,
+    path: &Path,
+    snapshot: bool,
+    key: Option<&Key>,
+) -> Verification {
+    let mut result = Verification {
+        name: name.to_string(),
+        files: 0,
+        manifest: true,
+        problems: Vec::new(),
+    };
+    let checked = if snapshot {
+        verify_snapshot(backup_dir, path, &mut result)
+    } else {
+        verify_zip(path, key, &mut result)
+    };
+    if let Err(why) = checked {
+        result.problems.push(why.to_string());
+    }
+    result
+}
+
+fn verify_zip(path: &Path, key: Option<&Key>, result: &mut Verification) -> BotResult<()> {
+    // A tampered encrypted archive is reported as such rather than as a
+    // list of files that do not decrypt.
+    let mut file = crypto::open(path, key)?;
+    file.authenticate()?;
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut manifest = None;
+    let mut found = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+        if name == manifest::MANIFEST_ENTRY {
+            let mut raw = Vec::new();
+            match file.read_to_end(&mut raw) {
+                Ok(_) => match serde_json::from_slice::<ZipManifest>(&raw) {
+                    Ok(parsed) => manifest = Some(parsed),
+                    Err(why) => result.problems.push(format!("{}: {}", name, why)),
+                },
+                Err(why) => result.problems.push(format!("{}: {}", name, why)),
+            }
+            continue;
+        }
+        // The zip reader checks each entry's CRC once it has been read to the end.
+        match digest(&mut file) {
+            Ok(digest) => {
+                found.insert(name, digest);
+            }
+            Err(why) => result.problems.push(format!("{}: {}", name, why)),
+        }
+    }
+
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => {
+            result.manifest = false;
+            result.files = found.len();
+            return Ok(());
+        }
+    };
+    result.files = manifest.files.len();
+    for record in &manifest.files {
+        match found.remove(&record.path) {
+            Some((size, sha256)) if size == record.size && sha256 == record.sha256 => {}
+            Some(_) => result
+                .problems
+                .push(format!("{}: チェックサムが一致しません", record.path)),
+            None => result
+                .problems
+                .push(format!("{}: ファイルがありません", record.path)),
+        }
+    }
+    for extra in found.keys() {
+        result
+            .problems
+            .push(format!("{}: マニフェストにないファイルです", extra));
+    }
+    Ok(())
+}
+
+fn verify_snapshot(backup_dir: &Path, path: &Path, result: &mut Verification) -> BotResult<()> {
+    let manifest = Manifest::load(path)?;
+    for entry in manifest.entries.iter().filter(|e| !e.dir) {
+        result.files += 1;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut readable = true;
+        for hash in &entry.chunks {
+            match snapshot::read_chunk(backup_dir, hash) {
+                Ok(chunk) => {
+                    hasher.update(&chunk);
+                    size += chunk.len() as u64;
+                }
+                Err(why) => {
+                    result.problems.push(format!("{}: {}", entry.path, why));
+                    readable = false;
+                    break;
+                }
+            }
+        }
+        if !readable {
+            continue;
+        }
+        let sha256 = hex::encode(hasher.finalize());
+        if size != entry.size || entry.sha256.as_ref().is_some_and(|h| *h != sha256) {
+            result
+                .problems
+                .push(format!("{}: チェックサムが一致しません", entry.path));
+        }
+    }
+    Ok(())
+}
+
+// The size and SHA-256 of everything `reader` yields.
+fn digest(reader: &mut impl Read) -> std::io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut size = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{create_backup, Progress};
+    use crate::config::BackupFormat;
+    use crate::manifest::Trigger;
+    use crate::test_support::{config, server_config};
+    use std::fs::File;
+    use tokio::sync::watch;
+
+    async fn backed_up(
+        format: BackupFormat,
+        key: Option<&str>,
+    ) -> (tempfile::TempDir, ServerConfig, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = server_config(dir.path());
+        server.format = format;
+        server.encryption_key = key.map(str::to_string);
+        server.compression = crate::config::Compression::Stored;
+        std::fs::create_dir_all(server.savedata_path.join("SaveProfiles")).unwrap();
+        std::fs::create_dir_all(&server.backup_dir).unwrap();
+        std::fs::write(
+            server.savedata_path.join("TheIsland.ark"),
+            "map ".repeat(1000),
+        )
+        .unwrap();
+        std::fs::write(server.savedata_path.join("Ragnarok.ark"), "small").unwrap();
+        std::fs::write(
+            server.savedata_path.join("SaveProfiles/1.arkprofile"),
+            "profile",
+        )
+        .unwrap();
+        let version = dir.path().join("version.txt");
+        std::fs::write(&version, "358.24\n").unwrap();
+        server.version_file = Some(version);
+        let config = config(vec![("island", server.clone())]);
+        let (progress, _) = watch::channel(Progress::default());
+        create_backup(&config, &server, Trigger::Manual, Some(3), progress)
+            .await
+            .unwrap();
+        let name = retention::scan(&server.backup_dir).unwrap()[0].name.clone();
+        (dir, server, name)
+    }
+
+    #[tokio::test]
+    async fn zip_manifests_record_the_server_and_catch_corruption() {
+        let (_dir, server, name) = backed_up(BackupFormat::Zip, None).await;
+        let path = server.backup_dir.join(format!("{}.zip", name));
+        let mut archive = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let manifest: ZipManifest =
+            serde_json::from_reader(archive.by_name(manifest::MANIFEST_ENTRY).unwrap()).unwrap();
+        assert_eq!(manifest.info.map.as_deref(), Some("TheIsland"));
+        assert_eq!(manifest.info.server_version.as_deref(), Some("358.24"));
+        assert_eq!(manifest.info.players, Some(3));
+        assert_eq!(manifest.info.trigger, Some(Trigger::Manual));
+        assert_eq!(manifest.files.len(), 3);
+
+        let results = verify_backups(&server, "all").await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok() && results[0].manifest);
+        assert_eq!(results[0].files, 3);
+
+        // Flip a byte of the stored (uncompressed) map data.
+        let mut raw = std::fs::read(&path).unwrap();
+        let offset = raw.windows(4).position(|w| w == b"map ").unwrap();
+        raw[offset] = b'M';
+        std::fs::write(&path, raw).unwrap();
+        let results = verify_backups(&server, &name).await.unwrap();
+        assert!(!results[0].is_ok());
+        assert!(results[0].problems[0].starts_with("TheIsland.ark"));
+
+        assert!(matches!(
+            verify_backups(&server, "2000-01-01_(00-00-00)").await,
+            Err(BotError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_checked_against_file_hashes() {
+        let (_dir, server, name) = backed_up(BackupFormat::Dedup, None).await;
+        let manifest = Manifest::load(&snapshot::manifest_path(&server.backup_dir, &name)).unwrap();
+        assert_eq!(manifest.info.map.as_deref(), Some("TheIsland"));
+        assert!(verify_backups(&server, &name).await.unwrap()[0].is_ok());
+
+        let profile = manifest
+            .entries
+            .iter()
+            .find(|e| e.path.ends_with("1.arkprofile"))
+            .unwrap();
+        let object = walkdir::WalkDir::new(server.backup_dir.join("objects"))
+            .into_iter()
+            .map(|e| e.unwrap())
+            .find(|e| e.file_name().to_str() == Some(profile.chunks[0].as_str()))
+            .unwrap();
+        std::fs::write(object.path(), b"garbage").unwrap();
+        let results = verify_backups(&server, "all").await.unwrap();
+        assert_eq!(results[0].problems.len(), 1);
+        assert!(results[0].problems[0].contains("1.arkprofile"));
+    }
+
+    #[tokio::test]
+    async fn encrypted_zips_are_authenticated() {
+        let key = "0f".repeat(32);
+        let (_dir, server, name) = backed_up(BackupFormat::Zip, Some(&key)).await;
+        let results = verify_backups(&server, &name).await.unwrap();
+        assert!(results[0].is_ok() && results[0].manifest);
+        assert_eq!(results[0].files, 3);
+
+        let mut other = server.clone();
+        other.encryption_key = Some("f0".repeat(32));
+        let results = verify_backups(&other, &name).await.unwrap();
+        assert!(results[0].problems[0].contains("authentication"));
+
+        // Every byte is covered, the nonce prefix in the header included.
+        let path = server.backup_dir.join(format!("{}.zip", name));
+        let mut raw = std::fs::read(&path).unwrap();
+        raw[10] ^= 0x80;
+        std::fs::write(&path, raw).unwrap();
+        let results = verify_backups(&server, &name).await.unwrap();
+        assert_eq!(results[0].problems.len(), 1);
+        assert!(results[0].problems[0].contains("authentication"));
+    }
+}